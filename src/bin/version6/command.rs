@@ -0,0 +1,301 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// A command entered on the `:` command line.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command {
+    Write(Option<PathBuf>),
+    WriteQuit,
+    Quit { force :bool },
+    Edit { path :Option<PathBuf>, force :bool },
+    /// Go to a line, such as `42`, `$` or `.`.
+    Goto(Address),
+    Set(Vec<String>),
+    Substitute(Substitute),
+    /// List the ends of the branches of the undo tree.
+    UndoList,
+    /// Go back in the undo history, across branches.
+    Earlier(Travel),
+    /// Go forward in the undo history, across branches.
+    Later(Travel),
+    /// Forget the named marks.
+    DeleteMarks(Vec<char>),
+}
+
+/// How far `:earlier` and `:later` go.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Travel {
+    /// A number of changes.
+    Steps(usize),
+    /// A span of time, typed as e.g. `30s` or `5m`.
+    Time(Duration),
+}
+
+/// A line in a range, as typed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Address {
+    /// A line numbered from 1.
+    Line(usize),
+    /// The cursor line, `.`.
+    Current,
+    /// The last line, `$`.
+    Last,
+}
+
+/// The first and last line a command applies to.
+pub type LineRange = (Address, Address);
+
+/// `:[range]s/pattern/replacement/[flags]`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Substitute {
+    /// `None` for the cursor line.
+    pub range :Option<LineRange>,
+    pub pattern :String,
+    pub replacement :String,
+    /// Replace every match on a line rather than the first, the `g` flag.
+    pub global :bool,
+    /// Ask before each replacement, the `c` flag.
+    pub confirm :bool,
+    /// Set by the `i` and `I` flags to override 'ignorecase'.
+    pub ignore_case :Option<bool>,
+}
+
+/// Parse a command line. Returns `Ok(None)` for an empty line.
+pub fn parse(line :&str) -> Result<Option<Command>, String> {
+    let line = line.trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let (range, line) = parse_range(line)?;
+    // A range alone goes to its last line.
+    if let (Some((_, last)), "") = (range, line) {
+        return Ok(Some(Command::Goto(last)));
+    }
+    if let Some(rest) = line.strip_prefix("substitute").or_else(|| line.strip_prefix('s')) {
+        if rest.starts_with(is_delimiter) {
+            return parse_substitute(range, rest).map(Some);
+        }
+    }
+    if range.is_some() {
+        return Err(format!("Not an editor command: {}", line));
+    }
+
+    let name_len = line.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(line.len());
+    let (name, rest) = line.split_at(name_len);
+    let (force, arg) = match rest.strip_prefix('!') {
+        Some(arg) => (true, arg.trim()),
+        None => (false, rest.trim()),
+    };
+    if !rest.is_empty() && !force && !rest.starts_with(char::is_whitespace) {
+        return Err(format!("Not an editor command: {}", line));
+    }
+    let path = if arg.is_empty() { None } else { Some(PathBuf::from(arg)) };
+    let no_arg = |command| if arg.is_empty() { Ok(Some(command)) } else { Err(format!("Trailing characters: {}", arg)) };
+
+    match name {
+        "w" | "write" if !force => Ok(Some(Command::Write(path))),
+        "wq" | "x" | "xit" if !force => no_arg(Command::WriteQuit),
+        "q" | "quit" => no_arg(Command::Quit { force }),
+        "e" | "edit" => Ok(Some(Command::Edit { path, force })),
+        "se" | "set" if !force => Ok(Some(Command::Set(arg.split_whitespace().map(|s| s.to_string()).collect()))),
+        "undol" | "undolist" if !force => no_arg(Command::UndoList),
+        "ea" | "earlier" if !force => parse_travel(arg).map(|travel| Some(Command::Earlier(travel))),
+        "lat" | "later" if !force => parse_travel(arg).map(|travel| Some(Command::Later(travel))),
+        "delm" | "delmarks" => parse_marks(arg, force).map(|marks| Some(Command::DeleteMarks(marks))),
+        _ => Err(format!("Not an editor command: {}", line)),
+    }
+}
+
+/// The argument of `:earlier` and `:later`: a count, 1 if empty, or a time
+/// in seconds, minutes, hours or days with `s`, `m`, `h` or `d`.
+fn parse_travel(arg :&str) -> Result<Travel, String> {
+    if arg.is_empty() {
+        return Ok(Travel::Steps(1));
+    }
+    let invalid = || format!("Invalid argument: {}", arg);
+    let digits = arg.find(|c :char| !c.is_ascii_digit()).unwrap_or(arg.len());
+    let n = arg[..digits].parse::<u64>().map_err(|_| invalid())?;
+    let unit = match &arg[digits..] {
+        "" => return Ok(Travel::Steps(n as usize)),
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return Err(invalid()),
+    };
+    Ok(Travel::Time(Duration::from_secs(n.saturating_mul(unit))))
+}
+
+/// The argument of `:delmarks`: mark names and ranges such as `b-d`, with
+/// or without spaces between them, or nothing after `!` for every mark.
+fn parse_marks(arg :&str, all :bool) -> Result<Vec<char>, String> {
+    if all {
+        return if arg.is_empty() { Ok(('a'..='z').collect()) } else { Err(format!("Trailing characters: {}", arg)) };
+    }
+    if arg.is_empty() {
+        return Err("Argument required".to_string());
+    }
+    let invalid = || format!("Invalid argument: {}", arg);
+    let chars = arg.chars().filter(|c| !c.is_whitespace()).collect::<Vec<_>>();
+    let mut marks = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let first = chars[i];
+        if !first.is_ascii_lowercase() {
+            return Err(invalid());
+        }
+        if chars.get(i + 1) != Some(&'-') {
+            marks.push(first);
+            i += 1;
+            continue;
+        }
+        match chars.get(i + 2) {
+            Some(&last) if last.is_ascii_lowercase() && last >= first => marks.extend(first..=last),
+            _ => return Err(invalid()),
+        }
+        i += 3;
+    }
+    Ok(marks)
+}
+
+fn parse_address(line :&str) -> (Option<Address>, &str) {
+    if let Some(rest) = line.strip_prefix('.') {
+        return (Some(Address::Current), rest);
+    }
+    if let Some(rest) = line.strip_prefix('$') {
+        return (Some(Address::Last), rest);
+    }
+    let digits = line.find(|c :char| !c.is_ascii_digit()).unwrap_or(line.len());
+    match line[..digits].parse::<usize>() {
+        Ok(n) => (Some(Address::Line(n)), &line[digits..]),
+        Err(_) => (None, line),
+    }
+}
+
+/// Split a line range such as `%`, `3` or `.,$` off the start of a command.
+fn parse_range(line :&str) -> Result<(Option<LineRange>, &str), String> {
+    if let Some(rest) = line.strip_prefix('%') {
+        return Ok((Some((Address::Line(1), Address::Last)), rest));
+    }
+    let (first, rest) = match parse_address(line) {
+        (Some(first), rest) => (first, rest),
+        (None, rest) => return Ok((None, rest)),
+    };
+    match rest.strip_prefix(',') {
+        Some(rest) => match parse_address(rest) {
+            (Some(last), rest) => Ok((Some((first, last)), rest)),
+            (None, _) => Err(format!("Invalid range: {}", line)),
+        },
+        None => Ok((Some((first, first)), rest)),
+    }
+}
+
+fn is_delimiter(c :char) -> bool {
+    !c.is_alphanumeric() && !c.is_whitespace() && !"\\\"|".contains(c)
+}
+
+/// Split `text` at the first `delimiter` not escaped by a backslash. An
+/// escaped delimiter stands for itself; other escapes are kept.
+fn split_delimited(text :&str, delimiter :char) -> (String, Option<&str>) {
+    let mut part = String::new();
+    let mut chars = text.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == delimiter {
+            return (part, Some(&text[i + c.len_utf8()..]));
+        }
+        if c == '\\' {
+            match chars.next() {
+                Some((_, next)) if next == delimiter => part.push(next),
+                Some((_, next)) => { part.push(c); part.push(next); },
+                None => part.push(c),
+            }
+        } else {
+            part.push(c);
+        }
+    }
+    (part, None)
+}
+
+fn parse_substitute(range :Option<LineRange>, text :&str) -> Result<Command, String> {
+    let delimiter = text.chars().next().unwrap();
+    let (pattern, rest) = split_delimited(&text[delimiter.len_utf8()..], delimiter);
+    let (replacement, flags) = match rest {
+        Some(rest) => split_delimited(rest, delimiter),
+        None => (String::new(), None),
+    };
+    let mut substitute = Substitute { range, pattern, replacement, global: false, confirm: false, ignore_case: None };
+    for flag in flags.unwrap_or("").trim_end().chars() {
+        match flag {
+            'g' => substitute.global = true,
+            'c' => substitute.confirm = true,
+            'i' => substitute.ignore_case = Some(true),
+            'I' => substitute.ignore_case = Some(false),
+            _ => return Err(format!("Trailing characters: {}", flags.unwrap())),
+        }
+    }
+    Ok(Command::Substitute(substitute))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Address, Command, Substitute, Travel};
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    #[test]
+    fn commands() {
+        assert_eq!(parse("  "), Ok(None));
+        assert_eq!(parse("w"), Ok(Some(Command::Write(None))));
+        assert_eq!(parse("w out.txt"), Ok(Some(Command::Write(Some(PathBuf::from("out.txt"))))));
+        assert_eq!(parse("q"), Ok(Some(Command::Quit { force: false })));
+        assert_eq!(parse("q!"), Ok(Some(Command::Quit { force: true })));
+        assert_eq!(parse("wq"), Ok(Some(Command::WriteQuit)));
+        assert_eq!(parse("e! a b"), Ok(Some(Command::Edit { path: Some(PathBuf::from("a b")), force: true })));
+        assert_eq!(parse("42"), Ok(Some(Command::Goto(Address::Line(42)))));
+        assert_eq!(parse("$"), Ok(Some(Command::Goto(Address::Last))));
+        assert_eq!(parse(" . "), Ok(Some(Command::Goto(Address::Current))));
+        assert_eq!(parse("2,$"), Ok(Some(Command::Goto(Address::Last))));
+        assert!(parse("$x").is_err());
+        assert_eq!(parse("set nonumber number?"),
+                   Ok(Some(Command::Set(vec!["nonumber".to_string(), "number?".to_string()]))));
+        assert!(parse("wx").is_err());
+        assert!(parse("q now").is_err());
+        assert!(parse("w!").is_err());
+        assert!(parse("q2").is_err());
+        assert_eq!(parse("undol"), Ok(Some(Command::UndoList)));
+        assert_eq!(parse("earlier"), Ok(Some(Command::Earlier(Travel::Steps(1)))));
+        assert_eq!(parse("ea 3"), Ok(Some(Command::Earlier(Travel::Steps(3)))));
+        assert_eq!(parse("later 30s"), Ok(Some(Command::Later(Travel::Time(Duration::from_secs(30))))));
+        assert_eq!(parse("lat 2h"), Ok(Some(Command::Later(Travel::Time(Duration::from_secs(7200))))));
+        assert!(parse("ea 5x").is_err());
+        assert!(parse("lat m").is_err());
+        assert_eq!(parse("delm a c-e"), Ok(Some(Command::DeleteMarks(vec!['a', 'c', 'd', 'e']))));
+        assert_eq!(parse("delmarks!"), Ok(Some(Command::DeleteMarks(('a'..='z').collect()))));
+        assert!(parse("delm").is_err());
+        assert!(parse("delm e-c").is_err());
+        assert!(parse("delm A").is_err());
+        assert!(parse("delm! a").is_err());
+    }
+
+    #[test]
+    fn substitute() {
+        let s = |range, pattern :&str, replacement :&str, global, confirm| Ok(Some(Command::Substitute(Substitute {
+            range, pattern: pattern.to_string(), replacement: replacement.to_string(), global, confirm, ignore_case: None,
+        })));
+        assert_eq!(parse("s/a/b/"), s(None, "a", "b", false, false));
+        assert_eq!(parse("s/a/b"), s(None, "a", "b", false, false));
+        assert_eq!(parse("s/a"), s(None, "a", "", false, false));
+        assert_eq!(parse("%s/a\\/b/\\1&/gc"), s(Some((Address::Line(1), Address::Last)), "a/b", "\\1&", true, true));
+        assert_eq!(parse("substitute#a/b#c#g"), s(None, "a/b", "c", true, false));
+        assert_eq!(parse("3s/a/b/"), s(Some((Address::Line(3), Address::Line(3))), "a", "b", false, false));
+        assert_eq!(parse(".,$s/a\\d/b/"), s(Some((Address::Current, Address::Last)), "a\\d", "b", false, false));
+        match parse("s/a/b/I") {
+            Ok(Some(Command::Substitute(s))) => assert_eq!(s.ignore_case, Some(false)),
+            other => panic!("{:?}", other),
+        }
+        assert!(parse("s/a/b/x").is_err());
+        assert!(parse("%w").is_err());
+        assert!(parse("1,s/a/b/").is_err());
+        assert_eq!(parse("set"), Ok(Some(Command::Set(vec![]))));
+    }
+}
@@ -1,17 +1,308 @@
-use fenwick::Fenwick;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::ops::{Deref, Range};
+use std::path::Path;
+use std::str;
+use std::sync::Arc;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use treap::{self, Treap, Weight};
+use undofile::{Decoder, Encoder};
+use unicode_segmentation::{GraphemeCursor, GraphemeIncomplete};
+use unicode_width::UnicodeWidthStr;
 
+/// Bytes of a file read, validated and indexed at a time when loading,
+/// which make one segment of the original buffer.
+const LOAD_CHUNK :usize = 1 << 20;
+
+/// Bytes of text between checkpoints in a segment.
+const BLOCK :usize = 1 << 12;
+/// Bytes of inserted text kept together in a segment of the append buffer.
+const SEGMENT :usize = 1 << 16;
+
+/// The number of characters and line breaks before a byte offset, which is
+/// on a character boundary.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Checkpoint {
+    byte :usize,
+    chars :usize,
+    lines :usize,
+}
+
+fn is_char_start(b :u8) -> bool {
+    b & 0xc0 != 0x80
+}
+
+/// The number of characters and line breaks in `bytes`.
+fn count(bytes :&[u8]) -> Checkpoint {
+    let chars = bytes.iter().filter(|b| is_char_start(**b)).count();
+    let lines = bytes.iter().filter(|b| **b == b'\n').count();
+    Checkpoint { byte: bytes.len(), chars, lines }
+}
+
+/// Read the next part of `file`, about `chunk` bytes ending on a character
+/// boundary, and make it a segment. `carry` holds the start of a character
+/// cut off at the end of the last part, and `start` is the byte offset of
+/// the part in the file. Returns None at the end of the file.
+fn read_chunk(file :&mut File, carry :&mut Vec<u8>, start :usize, chunk :usize) -> Result<Option<Segment>, String> {
+    let mut bytes = mem::take(carry);
+    loop {
+        let read = file.by_ref().take(chunk as u64).read_to_end(&mut bytes).map_err(|e| e.to_string())?;
+        let valid = match str::from_utf8(&bytes) {
+            Ok(text) => text.len(),
+            // A character cut off by the end of the read is finished by the next.
+            Err(ref e) if e.error_len().is_none() && read > 0 => e.valid_up_to(),
+            Err(e) => return Err(format!("Invalid UTF-8 at byte {}", start + e.valid_up_to())),
+        };
+        if valid == 0 && read > 0 { continue; }
+        *carry = bytes.split_off(valid);
+        if bytes.is_empty() { return Ok(None); }
+        // The bytes were checked above.
+        return Ok(Some(Segment::new(unsafe { String::from_utf8_unchecked(bytes) })));
+    }
+}
+
+/// Display width of a grapheme cluster in columns: two for wide East Asian
+/// characters and emoji, and at least one for anything else.
+pub fn width(grapheme :&str) -> usize {
+    usize::max(1, grapheme.width())
+}
+
+/// A part of a buffer. Positions in a segment are byte offsets from its
+/// start, which are always on character boundaries.
+struct Segment {
+    /// Byte offset of the segment in its buffer.
+    start :usize,
+    text :String,
+    /// Counts at the first character of every block of `BLOCK` bytes, to
+    /// find characters and lines by scanning at most one block.
+    checkpoints :Vec<Checkpoint>,
+    /// Counts at the end of the text.
+    end :Checkpoint,
+}
+
+impl Segment {
+    fn new(text :String) -> Segment {
+        let mut segment = Segment { start: 0, text, checkpoints: vec![Checkpoint::default()], end: Checkpoint::default() };
+        segment.index();
+        segment
+    }
+
+    fn text(&self) -> &str {
+        &self.text
+    }
+
+    fn len(&self) -> usize {
+        self.end.byte
+    }
+
+    fn push_str(&mut self, text :&str) {
+        self.text.push_str(text);
+        self.index();
+    }
+
+    /// Add checkpoints for the text after `end`.
+    fn index(&mut self) {
+        let Segment { ref text, ref mut checkpoints, ref mut end, .. } = *self;
+        let mut next = (checkpoints[checkpoints.len() - 1].byte / BLOCK + 1) * BLOCK;
+        for (byte, b) in (end.byte..).zip(text[end.byte..].bytes()) {
+            if is_char_start(b) {
+                if byte >= next {
+                    checkpoints.push(Checkpoint { byte, ..*end });
+                    next = (byte / BLOCK + 1) * BLOCK;
+                }
+                end.chars += 1;
+            }
+            if b == b'\n' { end.lines += 1; }
+        }
+        end.byte = text.len();
+    }
+
+    /// The last checkpoint for which `before` holds.
+    fn checkpoint<F :Fn(&Checkpoint) -> bool>(&self, before :F) -> Checkpoint {
+        self.checkpoints[self.checkpoints.partition_point(before) - 1]
+    }
+
+    /// The counts at byte offset `idx`.
+    fn count(&self, idx :usize) -> Checkpoint {
+        let at = self.checkpoint(|c| c.byte <= idx);
+        let counted = count(&self.text().as_bytes()[at.byte..idx]);
+        Checkpoint { byte: idx, chars: at.chars + counted.chars, lines: at.lines + counted.lines }
+    }
+
+    /// The number of characters and line breaks in `range`.
+    fn count_range(&self, range :Range<usize>) -> Checkpoint {
+        if range.end - range.start <= BLOCK {
+            return count(&self.text().as_bytes()[range]);
+        }
+        let (start, end) = (self.count(range.start), self.count(range.end));
+        Checkpoint { byte: end.byte - start.byte, chars: end.chars - start.chars, lines: end.lines - start.lines }
+    }
+
+    /// Byte offset of the character `chars` characters after byte offset
+    /// `idx`, or of the end of the segment.
+    fn advance(&self, idx :usize, chars :usize) -> usize {
+        let (start, chars) = if chars <= BLOCK {
+            (idx, chars)
+        } else {
+            let chr = self.count(idx).chars + chars;
+            let at = self.checkpoint(|c| c.chars <= chr);
+            (at.byte, chr - at.chars)
+        };
+        let text = &self.text()[start..];
+        start + text.char_indices().nth(chars).map_or(text.len(), |(i,_)| i)
+    }
+
+    /// Byte offset of the `n`th line break after byte offset `idx`,
+    /// counting from zero.
+    fn newline(&self, idx :usize, n :usize) -> usize {
+        let line = self.count(idx).lines + n;
+        let at = self.checkpoint(|c| c.lines <= line);
+        let newlines = self.text().as_bytes()[at.byte..].iter().enumerate().filter(|(_,b)| **b == b'\n');
+        at.byte + newlines.map(|(i,_)| i).nth(line - at.lines).expect("Line break after end of buffer")
+    }
+
+    /// Length in bytes of the character at byte offset `idx`.
+    fn char_len(&self, idx :usize) -> usize {
+        self.text()[idx..].chars().next().map(char::len_utf8).unwrap_or(0)
+    }
+}
+
+/// Text held as UTF-8, in segments which no piece crosses. Positions in a
+/// buffer are byte offsets, which are always on character boundaries.
+///
+/// Segments are shared with the snapshots of the document, and text is only
+/// added to a segment which is not shared, so that taking a snapshot or
+/// editing while one is alive never copies the text.
+#[derive(Clone, Default)]
 pub struct Buffer {
-    text :Vec<char>,
+    segments :Vec<Arc<Segment>>,
+    len :usize,
 }
 
-pub struct Document {
+impl Buffer {
+    pub fn new(text :String) -> Buffer {
+        let mut buffer = Buffer::default();
+        if !text.is_empty() {
+            buffer.add(Segment::new(text));
+        }
+        buffer
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Add `segment` to the end of the buffer.
+    fn add(&mut self, mut segment :Segment) {
+        segment.start = self.len;
+        self.len += segment.len();
+        self.segments.push(Arc::new(segment));
+    }
+
+    /// Add `text` to the end of the buffer. It goes in a new segment if the
+    /// last one is full or shared with a snapshot.
+    pub fn push_str(&mut self, text :&str) {
+        if text.is_empty() { return; }
+        match self.segments.last_mut().and_then(Arc::get_mut) {
+            Some(ref mut segment) if segment.len() + text.len() <= SEGMENT => {
+                segment.push_str(text);
+                self.len += text.len();
+            },
+            _ => {
+                let mut owned = String::with_capacity(usize::max(SEGMENT, text.len()));
+                owned.push_str(text);
+                self.add(Segment::new(owned));
+            },
+        }
+    }
+
+    /// Index of the segment containing byte offset `idx`.
+    fn segment_index(&self, idx :usize) -> usize {
+        self.segments.partition_point(|s| s.start <= idx) - 1
+    }
+
+    /// The segment containing byte offset `idx`, and the offset there.
+    fn segment(&self, idx :usize) -> (&Segment, usize) {
+        let segment = &self.segments[self.segment_index(idx)];
+        (segment, idx - segment.start)
+    }
+
+    /// The `len` bytes at byte offset `idx`, if they are in one segment and
+    /// start and end on character boundaries.
+    fn get(&self, idx :usize, len :usize) -> Option<&str> {
+        if idx >= self.len { return None; }
+        let (segment, offset) = self.segment(idx);
+        segment.text().get(offset..offset.checked_add(len)?)
+    }
+}
+
+/// The text of a document at some point. Since the buffers only grow,
+/// a snapshot shares their segments and the piece tree with the document;
+/// it stays the same while the document is edited, and can be read on
+/// another thread.
+#[derive(Clone)]
+pub struct Snapshot {
     original :Buffer,
     append :Buffer,
+    pieces :Treap,
+}
 
-    pieces :Vec<Ref>,
-    length_sum: Fenwick,
+pub struct Document {
+    /// The current text.
+    text :Snapshot,
+
+    /// Every recorded change, indexed by the number of the state it leads
+    /// to. Node 0 stands for the text before any change.
+    undo_tree :Vec<UndoNode>,
+    /// The number of the current state in `undo_tree`.
+    state :usize,
+    transaction_depth :usize,
+    pending :Option<Change>,
+
+    /// Indexed parts of a file which is still being loaded.
+    loader :Option<Receiver<Result<Segment, String>>>,
+
+    /// Positions kept up to date by `run`, indexed by `AnchorId`.
+    anchors :Vec<Option<Anchor>>,
+    /// Listeners told about every change by `run`, indexed by `ObserverId`.
+    observers :Vec<Option<Box<dyn Observer>>>,
+}
+
+/// A batch of operations applied together, and the character index where
+/// the cursor should be placed when the batch is undone or redone.
+#[derive(Debug)]
+struct Change {
+    ops :Vec<DocOp>,
+    pos :usize,
+}
+
+/// A change in the undo tree, leading from the state `parent` to the state
+/// numbered by its own index. Parents are always older than their children.
+#[derive(Debug)]
+struct UndoNode {
+    change :Change,
+    parent :usize,
+    time :SystemTime,
+    /// The child that `redo` goes to: the one made or undone last.
+    redo :Option<usize>,
 }
 
+/// The end of a branch of the undo tree, as listed by `:undolist`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UndoLeaf {
+    /// The number of the state. States are numbered in the order they
+    /// were made, starting from 0 for the text before any change.
+    pub number :usize,
+    /// The number of changes from the text before any change.
+    pub changes :usize,
+    pub time :SystemTime,
+}
+
+/// A piece of the document: a byte offset and length in one of the buffers.
 #[derive(Copy, Clone,Debug, PartialEq, Eq)]
 pub enum Ref {
     Original(usize, usize),
@@ -46,9 +337,29 @@ impl Ref {
             Ref::Append(x,l) => Ref::Append(x,l-len),
         }
     }
+
+    fn start(&self) -> usize {
+        match self {
+            Ref::Original(x,_) => *x,
+            Ref::Append(x,_) => *x,
+        }
+    }
+
+    fn end(&self) -> usize {
+        self.start() + self.len()
+    }
+
+    fn same_buffer(&self, r :&Ref) -> bool {
+        matches!((self, r), (Ref::Original(..), Ref::Original(..)) | (Ref::Append(..), Ref::Append(..)))
+    }
+
+    /// Whether `r` starts within this piece.
+    fn contains(&self, r :&Ref) -> bool {
+        self.same_buffer(r) && self.start() <= r.start() && r.start() < self.end()
+    }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum DocOp {
     Remove(usize, Ref),
     Insert(usize, Ref),
@@ -66,342 +377,1175 @@ impl DocOp {
     }
 }
 
+/// Which way an anchor goes when text is inserted exactly where it is.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Gravity {
+    /// Stay before the inserted text, with the character before the anchor.
+    Left,
+    /// Move past the inserted text, with the character after the anchor.
+    Right,
+}
+
+/// A handle to a position which follows the text around it as the document
+/// is edited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct AnchorId(usize);
+
+struct Anchor {
+    pos :usize,
+    gravity :Gravity,
+    /// Removed characters the anchor stuck to. When its own character is
+    /// removed, the anchor stays where it was and sticks to the character
+    /// before, as with left gravity, until one of these is put back by undo
+    /// or redo. The oldest comes first.
+    removed :Vec<Ref>,
+}
+
+impl Anchor {
+    fn gravity(&self) -> Gravity {
+        if self.removed.is_empty() { self.gravity } else { Gravity::Left }
+    }
+}
+
+/// Characters inserted or removed at a character index.
+#[derive(Clone, Copy, Debug)]
+enum Splice {
+    Insert(usize, Ref),
+    Remove(usize, Ref),
+}
+
+/// The region changed by a `run` so far, as the pieces of its text before
+/// and after.
+#[derive(Default)]
+struct Diff {
+    start :usize,
+    old :Vec<Ref>,
+    new :Vec<Ref>,
+}
+
+/// A change to the text, made by an edit, undo or redo. The characters in
+/// `removed` were replaced by `inserted`; both may be empty.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Edit {
+    /// Character indices before the change.
+    pub removed :Range<usize>,
+    pub inserted :String,
+    /// The change in the number of lines.
+    pub line_delta :isize,
+}
+
+/// Something which keeps track of the text, like a syntax highlighter or
+/// a search cache. See `Document::subscribe`.
+pub trait Observer {
+    fn edited(&mut self, edit :&Edit);
+}
+
+impl<F :FnMut(&Edit)> Observer for F {
+    fn edited(&mut self, edit :&Edit) {
+        self(edit)
+    }
+}
+
+// The editor has no observers yet, so only the tests subscribe.
+#[cfg(test)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ObserverId(usize);
+
 impl Document {
 
-    pub fn to_string(&self) -> String {
-        let mut x = String::new();
-        for piece in &self.pieces {
-            for c in self.get_ref(piece) {
-                x.push(*c);
-            }
+    pub fn empty() -> Document {
+        Document::new(String::new())
+    }
+
+    fn with_original(original :Buffer) -> Document {
+        let mut doc = Document::with_buffers(original, Buffer::default());
+        let length = doc.original.len();
+        if length > 0 {
+            doc.run(&[DocOp::Insert(0, Ref::Original(0, length))]);
         }
-        x
+        doc
     }
 
-    pub fn prev_linebreak(&self, mut i :usize) -> Option<usize> {
-        // TODO this does too much work 
-        let s = self.to_string().chars().collect::<Vec<_>>();
-        i = usize::min(i,s.len()-1);
-        while i > 0 {
-            if s[i] == '\n' {
-                return Some(i);
-            }
-            i -= 1;
+    /// A document with no pieces yet.
+    fn with_buffers(original :Buffer, append :Buffer) -> Document {
+        Document {
+            text: Snapshot {
+                original,
+                append,
+                pieces: Treap::new(),
+            },
+            undo_tree: vec![UndoNode { change: Change { ops: Vec::new(), pos: 0 }, parent: 0, time: SystemTime::now(), redo: None }],
+            state: 0,
+            transaction_depth: 0,
+            pending: None,
+            loader: None,
+            anchors: Vec::new(),
+            observers: Vec::new(),
         }
-        None
     }
 
-    pub fn next_linebreak(&self, mut i :usize) -> Option<usize> {
-        // TODO this does too much work 
-        let s = self.to_string().chars().collect::<Vec<_>>();
-        while i < s.len() {
-            if s[i] == '\n' {
-                return Some(i);
+    pub fn new(text :String) -> Document {
+        Document::with_original(Buffer::new(text))
+    }
+
+    /// Open a file, reading and indexing the first part right away and the
+    /// rest on a background thread; `poll` adds it to the document as it is
+    /// ready. The text is copied into memory, so the document stays whole
+    /// if the file is changed or truncated by others while it is open.
+    pub fn open(path :&Path) -> io::Result<Document> {
+        Document::open_in_chunks(path, LOAD_CHUNK)
+    }
+
+    fn open_in_chunks(path :&Path, chunk :usize) -> io::Result<Document> {
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len() as usize;
+        let mut carry = Vec::new();
+        let first = read_chunk(&mut file, &mut carry, 0, chunk).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut doc = Document::empty();
+        let mut start = 0;
+        if let Some(first) = first {
+            start = first.len();
+            doc.extend_original(first);
+        }
+        if start < size {
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || {
+                loop {
+                    let segment = match read_chunk(&mut file, &mut carry, start, chunk) {
+                        Ok(Some(segment)) => segment,
+                        Ok(None) => return,
+                        Err(e) => {
+                            let _ = sender.send(Err(e));
+                            return;
+                        },
+                    };
+                    start += segment.len();
+                    if sender.send(Ok(segment)).is_err() { return; }
+                }
+            });
+            doc.loader = Some(receiver);
+        }
+        Ok(doc)
+    }
+
+    /// Add a segment to the original buffer and its text to the end of the
+    /// document. This is not an edit, and cannot be undone.
+    fn extend_original(&mut self, segment :Segment) {
+        let start = self.original.len();
+        let length = segment.len();
+        self.text.original.add(segment);
+        if length > 0 {
+            let end = self.pieces.len();
+            self.run(&[DocOp::Insert(end, Ref::Original(start, length))]);
+        }
+    }
+
+    /// Add the parts of the file indexed since the last call. Returns
+    /// whether the document grew, or an error if loading failed.
+    pub fn poll(&mut self) -> Result<bool, String> {
+        let mut grew = false;
+        loop {
+            let received = match self.loader {
+                Some(ref loader) => loader.try_recv(),
+                None => return Ok(grew),
+            };
+            match received {
+                Ok(Ok(segment)) => {
+                    self.extend_original(segment);
+                    grew = true;
+                },
+                Ok(Err(e)) => {
+                    self.loader = None;
+                    return Err(e);
+                },
+                Err(TryRecvError::Empty) => return Ok(grew),
+                Err(TryRecvError::Disconnected) => {
+                    self.loader = None;
+                    return Ok(grew);
+                },
             }
-            i += 1;
         }
-        None
     }
 
-    pub fn empty() -> Document {
-        Document {
-            original: Buffer { text: Vec::new() },
-            append:   Buffer { text: Vec::new() },
-            pieces: Vec::new(),
-            length_sum:Fenwick::new(),
+    /// True while a file is still being loaded in the background.
+    pub fn loading(&self) -> bool {
+        self.loader.is_some()
+    }
+
+    /// The text as it is now, which does not change with later edits.
+    pub fn snapshot(&self) -> Snapshot {
+        self.text.clone()
+    }
+
+    /// Write the buffers, the pieces and the undo tree, for `undofile`. An
+    /// open undo group is saved as a closed one.
+    pub fn encode_history(&self, out :&mut Encoder) {
+        encode_buffer(out, &self.original);
+        encode_buffer(out, &self.append);
+        out.usize(self.pieces.len());
+        for r in self.pieces.iter_from(0) {
+            encode_ref(out, r);
+        }
+        let pending = self.undo_tree.len();
+        out.usize(pending + self.pending.iter().count());
+        for (number, node) in self.undo_tree.iter().enumerate() {
+            let redo = if self.pending.is_some() && number == self.state { Some(pending) } else { node.redo };
+            encode_node(out, node.parent, node.time, redo, &node.change);
+        }
+        match self.pending {
+            Some(ref change) => {
+                encode_node(out, self.state, SystemTime::now(), None, change);
+                out.usize(pending);
+            },
+            None => out.usize(self.state),
         }
     }
 
-    pub fn new(text :String) -> Document {
-        let text :Vec<char> = text.chars().collect();
-        let length     = text.len();
-        let mut length_sum = Fenwick::new();
-        length_sum.add(0,length);
-        Document {
-            original: Buffer { text: text },
-            append:   Buffer { text: Vec::new() },
-            pieces:    vec![ Ref::Original(0, length) ],
-            length_sum:length_sum,
+    /// Read back what `encode_history` wrote. Pieces out of bounds or off
+    /// character boundaries, malformed trees and changes which do not apply
+    /// to the pieces they were recorded on are refused.
+    pub fn decode_history(input :&mut Decoder) -> Result<Document, String> {
+        let invalid = || "Invalid undo tree in undo file".to_string();
+        let mut text = || String::from_utf8(input.bytes()?.to_vec()).map_err(|_| "Invalid UTF-8 in undo file".to_string());
+        let original = Buffer::new(text()?);
+        let append = Buffer::new(text()?);
+        let mut doc = Document::with_buffers(original, append);
+        for idx in 0..input.usize()? {
+            let r = doc.decode_ref(input)?;
+            let weight = doc.weight(&r);
+            doc.text.pieces.insert(idx, r, weight);
+        }
+        let count = input.usize()?;
+        doc.undo_tree.clear();
+        for number in 0..count {
+            let parent = input.usize()?;
+            let time = UNIX_EPOCH + Duration::from_millis(input.u64()?);
+            let redo = input.usize()?.checked_sub(1);
+            let pos = input.usize()?;
+            let mut ops = Vec::new();
+            for _ in 0..input.usize()? {
+                let tag = input.u8()?;
+                let idx = input.usize()?;
+                ops.push(match tag {
+                    0 => DocOp::Remove(idx, doc.decode_ref(input)?),
+                    1 => DocOp::Insert(idx, doc.decode_ref(input)?),
+                    2 => DocOp::Set(idx, doc.decode_ref(input)?, doc.decode_ref(input)?),
+                    _ => return Err("Invalid operation in undo file".to_string()),
+                });
+            }
+            if (number > 0 && parent >= number) || redo.is_some_and(|redo| redo <= number || redo >= count) {
+                return Err(invalid());
+            }
+            doc.undo_tree.push(UndoNode { change: Change { ops, pos }, parent, time, redo });
         }
+        doc.state = input.usize()?;
+        let redo_to_child = doc.undo_tree.iter().enumerate()
+            .all(|(number, node)| node.redo.is_none_or(|redo| doc.undo_tree[redo].parent == number));
+        if doc.undo_tree.is_empty() || doc.state >= count || !redo_to_child || !doc.replays() {
+            return Err(invalid());
+        }
+        Ok(doc)
     }
 
-    fn get_ref(&self, r :&Ref) -> &[char] {
-        match r {
-            Ref::Original(idx,len) => &self.original.text[*idx..(idx+len)],
-            Ref::Append(idx,len) => &self.append.text[*idx..(idx+len)],
+    /// Whether every change in the undo tree applies to the pieces of its
+    /// parent state and leaves the cursor within the text. The pieces of
+    /// the first state are found by reverting from the current one.
+    fn replays(&self) -> bool {
+        let mut pieces = self.pieces.clone();
+        let mut number = self.state;
+        while number != 0 {
+            let mut inverse = self.undo_tree[number].change.ops.iter().rev().cloned().map(DocOp::inverse);
+            if !inverse.all(|op| self.replay(&mut pieces, &op)) { return false; }
+            number = self.undo_tree[number].parent;
+        }
+        let mut states = vec![pieces];
+        for node in &self.undo_tree[1..] {
+            let mut pieces = states[node.parent].clone();
+            let before = pieces.sum().chars;
+            if !node.change.ops.iter().all(|op| self.replay(&mut pieces, op)) { return false; }
+            if node.change.pos > usize::max(before, pieces.sum().chars) { return false; }
+            states.push(pieces);
         }
+        true
     }
 
-    pub fn len(&self) -> usize {
-        self.length_sum.suffix_sum(0)
+    /// Apply `op` to `pieces`, if they have the pieces it was made for.
+    fn replay(&self, pieces :&mut Treap, op :&DocOp) -> bool {
+        match *op {
+            DocOp::Insert(idx, r) if idx <= pieces.len() => pieces.insert(idx, r, self.weight(&r)),
+            DocOp::Remove(idx, r) if idx < pieces.len() && pieces.get(idx) == r => { pieces.remove(idx); },
+            DocOp::Set(idx, old, new) if idx < pieces.len() && pieces.get(idx) == old => pieces.set(idx, new, self.weight(&new)),
+            _ => return false,
+        }
+        true
     }
 
+    fn decode_ref(&self, input :&mut Decoder) -> Result<Ref, String> {
+        let tag = input.u8()?;
+        let (start, len) = (input.usize()?, input.usize()?);
+        let r = match tag {
+            0 => Ref::Original(start, len),
+            1 => Ref::Append(start, len),
+            _ => return Err("Invalid piece in undo file".to_string()),
+        };
+        let buffer = match r {
+            Ref::Original(..) => &self.original,
+            Ref::Append(..) => &self.append,
+        };
+        match buffer.get(start, len) {
+            Some(_) if len > 0 => Ok(r),
+            _ => Err("Invalid piece in undo file".to_string()),
+        }
+    }
+
+    /// Apply `ops`, then tell the observers what changed.
     pub fn run(&mut self, ops :&[DocOp]) {
+        let observed = !self.observers.is_empty();
+        let lines = self.line_count();
+        let mut diff :Option<Diff> = None;
         for op in ops {
+            if observed || !self.anchors.is_empty() {
+                for splice in self.splices(op) {
+                    match splice {
+                        Splice::Insert(pos, r) => self.anchors_inserted(pos, r),
+                        Splice::Remove(pos, r) => self.anchors_removed(pos, r),
+                    }
+                    if observed {
+                        self.add_splice(diff.get_or_insert_with(Diff::default), splice);
+                    }
+                }
+            }
             match op {
                 DocOp::Insert(idx, x) => {
-                    for i in *idx .. self.pieces.len() {
-                        self.length_sum.sub(i,   self.pieces[i].len());
-                        self.length_sum.add(i+1, self.pieces[i].len());
-                    }
-                    self.pieces.insert(*idx,*x);
-                    self.length_sum.add(*idx,x.len());
+                    let weight = self.weight(x);
+                    self.text.pieces.insert(*idx, *x, weight);
                 },
                 DocOp::Remove(idx, x) => {
-                    for i in idx+1 .. self.pieces.len() {
-                        self.length_sum.sub(i,   self.pieces[i].len());
-                        self.length_sum.add(i-1, self.pieces[i].len());
-                    }
-                    let old = self.pieces.remove(*idx);
+                    let old = self.text.pieces.remove(*idx);
                     assert_eq!(*x, old);
-                    self.length_sum.sub(*idx, x.len());
                 },
                 DocOp::Set(idx, old, new) => {
-                    self.length_sum.sub(*idx, old.len());
-                    self.length_sum.add(*idx, new.len());
-                    self.pieces[*idx] = *new;
+                    assert_eq!(*old, self.pieces.get(*idx));
+                    let weight = self.weight(new);
+                    self.text.pieces.set(*idx, *new, weight);
                 },
             }
-            println!("DOC OP {:?}", op);
-            self.print_prefixes();
-            println!("DOC OP {:?}", op);
+        }
+        if let Some(edit) = diff.and_then(|diff| self.edit(diff, lines)) {
+            for observer in self.observers.iter_mut().filter_map(Option::as_mut) {
+                observer.edited(&edit);
+            }
         }
     }
 
-    pub fn remove_actions(&mut self, idx: usize) -> Vec<DocOp> {
-        if self.pieces.len() == 0 { return vec![] };
-        if idx == 0 {
-            if self.pieces[0].len() == 1{
-                vec![DocOp::Remove(0, self.pieces[0])]
-            } else {
-                vec![DocOp::Set(0, self.pieces[0], self.pieces[0].skip(1))]
-            }
+    /// Add `splice`, which is about to be applied, to `diff`.
+    fn add_splice(&self, diff :&mut Diff, splice :Splice) {
+        let (pos, r, removed) = match splice {
+            Splice::Insert(pos, r) => (pos, r, 0),
+            Splice::Remove(pos, r) => (pos, r, self.ref_chars(&r)),
+        };
+        if diff.new.is_empty() && diff.old.is_empty() {
+            diff.start = pos;
+        }
+        // Grow the region to cover the splice. The text added at its ends
+        // is unchanged so far.
+        if pos < diff.start {
+            let refs = self.refs(pos..diff.start);
+            diff.old.splice(0..0, refs.iter().cloned());
+            diff.new.splice(0..0, refs);
+            diff.start = pos;
+        }
+        let end = diff.start + diff.new.iter().map(|r| self.ref_chars(r)).sum::<usize>();
+        if pos + removed > end {
+            let refs = self.refs(end..pos + removed);
+            diff.old.extend(refs.iter().cloned());
+            diff.new.extend(refs);
+        }
+        let at = self.split_refs(&mut diff.new, pos - diff.start);
+        if removed > 0 {
+            let to = self.split_refs(&mut diff.new, pos - diff.start + removed);
+            diff.new.drain(at..to);
         } else {
-            match self.length_sum.find_prefix(idx) {
-                Ok(piece_idx) => { // remove from start of piece_idx+1
-                    println!("remove: remove from start");
-                    let start_idx = piece_idx+1;
-                    if start_idx < self.pieces.len()  {
-                        if self.pieces[start_idx].len() > 1 {
-                            println!("remove: -set");
-                            vec![DocOp::Set(start_idx, self.pieces[start_idx], self.pieces[start_idx].skip(1))]
-                        } else {
-                            println!("remove: -remove piece");
-                            vec![DocOp::Remove(start_idx, self.pieces[start_idx])]
-                        }
-                    } else {
-                        panic!("Remove from after end of buffer");
-                    }
-                },
-                Err(piece_idx) => { // split piece
-                    println!("remove: split piece");
-                    if piece_idx > (self.pieces.len() -1) { panic!("Delete action after end of buffer"); }
-                    let length_before_piece = if piece_idx == 0 { 0 } else { self.length_sum.prefix_sum(piece_idx -1) };
-                    let (before,after) = self.pieces[piece_idx].split(idx-length_before_piece);
-
-                    if after.len() > 1 {
-                        println!("remove: -split");
-                        vec![DocOp::Remove(piece_idx, self.pieces[piece_idx]),
-                             DocOp::Insert(piece_idx, before),
-                             DocOp::Insert(piece_idx+1, after.skip(1))]
-                    } else {
-                        println!("remove: -set");
-                        vec![DocOp::Set(piece_idx, self.pieces[piece_idx], before)]
-                    }
+            diff.new.insert(at, r);
+        }
+    }
+
+    /// The edit made by `diff`, leaving out the text at its ends which is
+    /// the same before and after. `lines` is the line count before.
+    fn edit(&self, diff :Diff, lines :usize) -> Option<Edit> {
+        let (mut old, mut new) = (diff.old, diff.new);
+        let mut start = diff.start;
+        // Drop the common start, then the common end.
+        let (mut i, mut j) = (0, 0);
+        while i < old.len() && j < new.len() && old[i].same_buffer(&new[j]) && old[i].start() == new[j].start() {
+            let len = usize::min(old[i].len(), new[j].len());
+            start += self.ref_chars(&old[i].split(len).0);
+            old[i] = old[i].skip(len);
+            new[j] = new[j].skip(len);
+            if old[i].len() == 0 { i += 1; }
+            if new[j].len() == 0 { j += 1; }
+        }
+        let (mut old, mut new) = (old.split_off(i), new.split_off(j));
+        while let (Some(a), Some(b)) = (old.last().cloned(), new.last().cloned()) {
+            if !(a.same_buffer(&b) && a.end() == b.end()) { break; }
+            let len = usize::min(a.len(), b.len());
+            old.pop();
+            new.pop();
+            if len < a.len() { old.push(a.pop(len)); }
+            if len < b.len() { new.push(b.pop(len)); }
+        }
+        let removed = old.iter().map(|r| self.ref_chars(r)).sum::<usize>();
+        let inserted = new.iter().map(|r| self.get_ref(r)).collect::<String>();
+        if removed == 0 && inserted.is_empty() { return None; }
+        Some(Edit {
+            removed: start..start + removed,
+            inserted,
+            line_delta: self.line_count() as isize - lines as isize,
+        })
+    }
+
+    /// Have `observer` told about every change to the text.
+    #[cfg(test)]
+    pub fn subscribe(&mut self, observer :Box<dyn Observer>) -> ObserverId {
+        match self.observers.iter().position(Option::is_none) {
+            Some(idx) => {
+                self.observers[idx] = Some(observer);
+                ObserverId(idx)
+            },
+            None => {
+                self.observers.push(Some(observer));
+                ObserverId(self.observers.len() - 1)
+            },
+        }
+    }
+
+    #[cfg(test)]
+    pub fn unsubscribe(&mut self, id :ObserverId) -> Option<Box<dyn Observer>> {
+        self.observers.get_mut(id.0).and_then(Option::take)
+    }
+
+    /// Add an anchor at the character index `pos`.
+    pub fn add_anchor(&mut self, pos :usize, gravity :Gravity) -> AnchorId {
+        let anchor = Some(Anchor { pos: usize::min(pos, self.len()), gravity, removed: Vec::new() });
+        match self.anchors.iter().position(Option::is_none) {
+            Some(idx) => {
+                self.anchors[idx] = anchor;
+                AnchorId(idx)
+            },
+            None => {
+                self.anchors.push(anchor);
+                AnchorId(self.anchors.len() - 1)
+            },
+        }
+    }
+
+    /// Forget an anchor, for `:delmarks`. Its id may be given to a new one.
+    pub fn remove_anchor(&mut self, id :AnchorId) {
+        self.anchors[id.0] = None;
+    }
+
+    /// The character index of an anchor.
+    pub fn anchor(&self, id :AnchorId) -> usize {
+        self.anchors[id.0].as_ref().expect("Anchor was removed").pos
+    }
+
+    pub fn set_anchor(&mut self, id :AnchorId, pos :usize) {
+        let len = self.len();
+        let anchor = self.anchors[id.0].as_mut().expect("Anchor was removed");
+        anchor.pos = usize::min(pos, len);
+        anchor.removed.clear();
+    }
+
+    /// The characters `op` inserts and removes, as pieces or parts of
+    /// pieces at a character index. Changes at the ends of a piece are given
+    /// as inserting or removing only the affected part.
+    fn splices(&self, op :&DocOp) -> Vec<Splice> {
+        match *op {
+            DocOp::Insert(idx, x) => vec![Splice::Insert(self.pieces.prefix(idx).chars, x)],
+            DocOp::Remove(idx, x) => vec![Splice::Remove(self.pieces.prefix(idx).chars, x)],
+            DocOp::Set(idx, old, new) => {
+                let pos = self.pieces.prefix(idx).chars;
+                let (ol, nl) = (old.len(), new.len());
+                if nl > ol && new.split(ol).0 == old {
+                    vec![Splice::Insert(pos + self.ref_chars(&old), new.skip(ol))]
+                } else if nl < ol && old.split(nl).0 == new {
+                    vec![Splice::Remove(pos + self.ref_chars(&new), old.skip(nl))]
+                } else if nl > ol && new.skip(nl - ol) == old {
+                    vec![Splice::Insert(pos, new.split(nl - ol).0)]
+                } else if nl < ol && old.skip(ol - nl) == new {
+                    vec![Splice::Remove(pos, old.split(ol - nl).0)]
+                } else {
+                    vec![Splice::Remove(pos, old), Splice::Insert(pos, new)]
                 }
+            },
+        }
+    }
+
+    /// Move the anchors for the piece `r` being inserted at `pos`.
+    fn anchors_inserted(&mut self, pos :usize, r :Ref) {
+        let len = self.ref_chars(&r);
+        let mut anchors = mem::take(&mut self.anchors);
+        for anchor in anchors.iter_mut().filter_map(Option::as_mut) {
+            if let Some(i) = anchor.removed.iter().position(|c| r.contains(c)) {
+                let c = anchor.removed[i];
+                anchor.removed.truncate(i);
+                let before = self.ref_chars(&r.split(c.start() - r.start()).0);
+                anchor.pos = pos + before + if anchor.gravity() == Gravity::Left { 1 } else { 0 };
+            } else if anchor.pos > pos || (anchor.pos == pos && anchor.gravity() == Gravity::Right) {
+                anchor.pos += len;
             }
         }
+        self.anchors = anchors;
     }
 
-    pub fn insert_actions(&mut self, idx :usize, c:char) -> Vec<DocOp> {
-        let append_idx = self.append.text.len();
-        self.append.text.push(c);
+    /// Move the anchors for the piece `r` at `pos` being removed.
+    fn anchors_removed(&mut self, pos :usize, r :Ref) {
+        let len = self.ref_chars(&r);
+        let mut anchors = mem::take(&mut self.anchors);
+        for anchor in anchors.iter_mut().filter_map(Option::as_mut) {
+            let stuck = match anchor.gravity() {
+                Gravity::Left => anchor.pos.checked_sub(1),
+                Gravity::Right => Some(anchor.pos),
+            };
+            match stuck {
+                Some(c) if pos <= c && c < pos + len => {
+                    let (buffer, idx, _) = self.buffer(&r);
+                    let offset = self.byte_offset(&r, c - pos);
+                    anchor.removed.push(r.skip(offset).split(buffer.char_len(idx + offset)).0);
+                    anchor.pos = pos;
+                },
+                _ if anchor.pos >= pos + len => anchor.pos -= len,
+                _ if anchor.pos > pos => anchor.pos = pos,
+                _ => {},
+            }
+        }
+        self.anchors = anchors;
+    }
 
-        if idx == 0 { // TODO: get rid of this case somehow?
-            vec![DocOp::Insert(0, Ref::Append(append_idx, 1))]
-        } else {
-            match self.length_sum.find_prefix(idx) {
-                Ok(piece_idx) => { // Add to/after end of piece
-                    match self.pieces[piece_idx] {
-                        Ref::Original(_,_) => {
-                            println!("insert: orignal -> new append");
-                            vec![DocOp::Insert(piece_idx+1, Ref::Append(append_idx,1))]
-                        },
-                        Ref::Append(x,l) => {
-                            if x+l == append_idx {
-                                println!("insert: replace append");
-                                vec![DocOp::Set(piece_idx, self.pieces[piece_idx], Ref::Append(x,l+1))]
-                            } else {
-                                println!("insert: append and new append");
-                                vec![DocOp::Insert(piece_idx+1, Ref::Append(append_idx,1))]
-                            }
-                        }
-                    }
+    fn record(&mut self, mut ops :Vec<DocOp>, pos :usize) {
+        if ops.is_empty() { return; }
+        if self.transaction_depth > 0 {
+            match self.pending {
+                Some(ref mut change) => {
+                    change.ops.append(&mut ops);
+                    change.pos = usize::min(change.pos, pos);
                 },
-                Err(piece_idx) => {
-                    if piece_idx > (self.pieces.len() -1) { panic!("Insert after end of buffer"); }
-                    let length_before_piece = if piece_idx == 0 { 0 }  else { self.length_sum.prefix_sum(piece_idx-1 ) };
-                    let (before,after) = self.pieces[piece_idx].split(idx-length_before_piece);
-
-                    println!("insert: split");
-                    vec![DocOp::Remove(piece_idx, self.pieces[piece_idx]),
-                         DocOp::Insert(piece_idx, before),
-                         DocOp::Insert(piece_idx+1, Ref::Append(append_idx,1)),
-                         DocOp::Insert(piece_idx+2, after)]
+                None => self.pending = Some(Change { ops, pos }),
+            }
+        } else {
+            self.add_change(Change { ops, pos });
+        }
+    }
+
+    /// Add a change made in the current state to the undo tree, as a new
+    /// branch if changes made there before were undone.
+    fn add_change(&mut self, change :Change) {
+        let number = self.undo_tree.len();
+        self.undo_tree.push(UndoNode { change, parent: self.state, time: SystemTime::now(), redo: None });
+        self.undo_tree[self.state].redo = Some(number);
+        self.state = number;
+    }
+
+    /// Start grouping edits so that they are undone as a single change.
+    /// Transactions may be nested; the group is closed when the outermost
+    /// transaction is committed.
+    pub fn begin_transaction(&mut self) {
+        self.transaction_depth += 1;
+    }
+
+    pub fn commit_transaction(&mut self) {
+        assert!(self.transaction_depth > 0, "commit without matching begin_transaction");
+        self.transaction_depth -= 1;
+        if self.transaction_depth == 0 {
+            self.group_boundary();
+        }
+    }
+
+    /// Close the current undo group without ending the transaction, so that
+    /// edits before and after this point are undone separately.
+    pub fn group_boundary(&mut self) {
+        if let Some(change) = self.pending.take() {
+            self.add_change(change);
+        }
+    }
+
+    /// The number of the current state in the undo tree. Edits in an open
+    /// transaction become a state of their own when it is committed.
+    pub fn state(&self) -> usize {
+        self.state
+    }
+
+    pub fn can_undo(&self) -> bool { self.state != 0 || self.pending.is_some() }
+    pub fn can_redo(&self) -> bool { self.undo_tree[self.state].redo.is_some() }
+
+    /// Revert the last recorded change. Returns the position where the
+    /// change happened, or `None` if there is nothing to undo.
+    pub fn undo(&mut self) -> Option<usize> {
+        self.group_boundary();
+        if self.state == 0 { return None; }
+        Some(self.revert())
+    }
+
+    /// Re-apply the last undone change. Returns the position where the
+    /// change happened, or `None` if there is nothing to redo.
+    pub fn redo(&mut self) -> Option<usize> {
+        self.group_boundary();
+        let child = self.undo_tree[self.state].redo?;
+        Some(self.apply(child))
+    }
+
+    /// Revert the change leading to the current state, going to its parent.
+    fn revert(&mut self) -> usize {
+        let number = self.state;
+        let inverse = self.undo_tree[number].change.ops.iter().rev().cloned().map(DocOp::inverse).collect::<Vec<_>>();
+        self.run(&inverse);
+        self.state = self.undo_tree[number].parent;
+        self.undo_tree[self.state].redo = Some(number);
+        self.undo_tree[number].change.pos
+    }
+
+    /// Apply the change leading from the current state to its child `number`.
+    fn apply(&mut self, number :usize) -> usize {
+        let ops = mem::take(&mut self.undo_tree[number].change.ops);
+        self.run(&ops);
+        self.undo_tree[number].change.ops = ops;
+        self.undo_tree[self.state].redo = Some(number);
+        self.state = number;
+        self.undo_tree[number].change.pos
+    }
+
+    /// Go to state `number` of the undo tree, undoing changes up to the
+    /// state it branched off from and redoing those down to it. Returns
+    /// the position of the last change, or `None` if the state is current.
+    fn travel(&mut self, number :usize) -> Option<usize> {
+        self.group_boundary();
+        let mut down = Vec::new();
+        let (mut up, mut target) = (self.state, number);
+        while up != target {
+            if up > target {
+                up = self.undo_tree[up].parent;
+            } else {
+                down.push(target);
+                target = self.undo_tree[target].parent;
+            }
+        }
+        let mut pos = None;
+        while self.state != up {
+            pos = Some(self.revert());
+        }
+        for number in down.into_iter().rev() {
+            pos = Some(self.apply(number));
+        }
+        pos
+    }
+
+    /// Go back `steps` states in the order they were made, across branches,
+    /// as `g-` does.
+    pub fn earlier(&mut self, steps :usize) -> Option<usize> {
+        self.group_boundary();
+        let number = self.state.saturating_sub(steps);
+        self.travel(number)
+    }
+
+    /// Go forward `steps` states in the order they were made, as `g+` does.
+    pub fn later(&mut self, steps :usize) -> Option<usize> {
+        self.group_boundary();
+        let number = usize::min(self.undo_tree.len() - 1, self.state.saturating_add(steps));
+        self.travel(number)
+    }
+
+    /// Go to the last state made at least `span` before the current one.
+    pub fn earlier_by(&mut self, span :Duration) -> Option<usize> {
+        self.group_boundary();
+        let number = match self.undo_tree[self.state].time.checked_sub(span) {
+            Some(time) => self.undo_tree.iter().rposition(|node| node.time <= time).unwrap_or(0),
+            None => 0,
+        };
+        self.travel(usize::min(number, self.state))
+    }
+
+    /// Go to the last state made at most `span` after the current one.
+    pub fn later_by(&mut self, span :Duration) -> Option<usize> {
+        self.group_boundary();
+        let number = match self.undo_tree[self.state].time.checked_add(span) {
+            Some(time) => self.undo_tree.iter().rposition(|node| node.time <= time).unwrap_or(0),
+            None => self.undo_tree.len() - 1,
+        };
+        self.travel(usize::max(number, self.state))
+    }
+
+    /// The states at the ends of the branches of the undo tree, oldest first.
+    pub fn undo_leaves(&self) -> Vec<UndoLeaf> {
+        let mut changes = vec![0; self.undo_tree.len()];
+        let mut leaf = vec![true; self.undo_tree.len()];
+        leaf[0] = false;
+        for (number, node) in self.undo_tree.iter().enumerate().skip(1) {
+            changes[number] = changes[node.parent] + 1;
+            leaf[node.parent] = false;
+        }
+        self.undo_tree.iter().enumerate().filter(|&(number, _)| leaf[number])
+            .map(|(number, node)| UndoLeaf { number, changes: changes[number], time: node.time })
+            .collect()
+    }
+
+    pub fn remove_actions(&mut self, idx: usize) -> Vec<DocOp> {
+        if self.pieces.is_empty() { return vec![] };
+        self.remove_range_actions(idx..(idx+1))
+    }
+
+    /// Operations removing the characters in `range`. Pieces that are
+    /// entirely covered are removed, and at most the first and last piece
+    /// are trimmed (or split, if the range lies within a single piece).
+    pub fn remove_range_actions(&mut self, range :Range<usize>) -> Vec<DocOp> {
+        if range.start >= range.end { return vec![]; }
+        if range.end > self.len() { panic!("Remove from after end of buffer"); }
+
+        let (first_idx, first_before) = self.pieces.find_char(range.start);
+        let (last_idx, last_before)   = self.pieces.find_char(range.end - 1);
+        let first = self.pieces.get(first_idx);
+        let last  = self.pieces.get(last_idx);
+        let start_offset = self.byte_offset(&first, range.start - first_before.chars);
+        let end_offset   = self.byte_offset(&last, range.end - last_before.chars);
+
+        if first_idx == last_idx {
+            return match (start_offset, end_offset == first.len()) {
+                (0, true)  => vec![DocOp::Remove(first_idx, first)],
+                (0, false) => vec![DocOp::Set(first_idx, first, first.skip(end_offset))],
+                (_, true)  => vec![DocOp::Set(first_idx, first, first.split(start_offset).0)],
+                (_, false) => vec![DocOp::Set(first_idx, first, first.split(start_offset).0),
+                                   DocOp::Insert(first_idx+1, first.skip(end_offset))],
+            };
+        }
+
+        // Trim the partially covered end pieces first, then remove whole
+        // pieces from the back so that the indices stay valid.
+        let mut ops = Vec::new();
+        let mut remove_from = first_idx;
+        let mut remove_to = last_idx;
+        if start_offset > 0 {
+            ops.push(DocOp::Set(first_idx, first, first.split(start_offset).0));
+            remove_from += 1;
+        }
+        if end_offset < last.len() {
+            ops.push(DocOp::Set(last_idx, last, last.skip(end_offset)));
+        } else {
+            remove_to += 1;
+        }
+        for i in (remove_from..remove_to).rev() {
+            ops.push(DocOp::Remove(i, self.pieces.get(i)));
+        }
+        ops
+    }
+
+    pub fn insert_actions(&mut self, idx :usize, c:char) -> Vec<DocOp> {
+        self.insert_str_actions(idx, c.encode_utf8(&mut [0; 4]))
+    }
+
+    /// Operations inserting `text` at `idx`. The text is added to the append
+    /// buffer as a single piece, which extends the preceding piece if that
+    /// piece ends where the text was added, in the same segment.
+    pub fn insert_str_actions(&mut self, idx :usize, text :&str) -> Vec<DocOp> {
+        if idx > self.len() { panic!("Insert after end of buffer"); }
+        let append_idx = self.append.len();
+        self.text.append.push_str(text);
+        let length = text.len();
+        if length == 0 { return vec![]; }
+        let new = Ref::Append(append_idx, length);
+
+        let (piece_idx, before) = self.pieces.find_char(idx);
+        if idx > before.chars { // split piece
+            let piece = self.pieces.get(piece_idx);
+            let (first,second) = piece.split(self.byte_offset(&piece, idx - before.chars));
+            return vec![DocOp::Set(piece_idx, piece, first),
+                        DocOp::Insert(piece_idx+1, new),
+                        DocOp::Insert(piece_idx+2, second)];
+        }
+
+        // Add to/after end of the previous piece
+        if piece_idx > 0 {
+            if let Ref::Append(x,l) = self.pieces.get(piece_idx-1) {
+                if x+l == append_idx && self.append.segment_index(x) == self.append.segment_index(append_idx) {
+                    return vec![DocOp::Set(piece_idx-1, Ref::Append(x,l), Ref::Append(x,l+length))];
                 }
             }
         }
+        vec![DocOp::Insert(piece_idx, new)]
+    }
+
+    pub fn insert_str(&mut self, idx :usize, text :&str) {
+        let actions = self.insert_str_actions(idx, text);
+        self.run(&actions);
+        self.record(actions, idx);
+    }
+
+    pub fn remove_range(&mut self, range :Range<usize>) {
+        let start = range.start;
+        let actions = self.remove_range_actions(range);
+        self.run(&actions);
+        self.record(actions, start);
     }
 
     pub fn insert(&mut self, idx :usize, c:char) {
-          println!("OLD piece table:");
-          for x in &self.pieces {
-              println!("  - {:?}", x);
-          }
-          println!("  orig:{}\n  apnd:{}", self.original.text.iter().collect::<String>(), self.append.text.iter().collect::<String>());
-          println!("  FIND {}@ {:?}", idx, self.length_sum.find_prefix(idx));
-      
         let actions = self.insert_actions(idx, c);
-        println!("ACTIONS: {:?}", actions);
         self.run(&actions);
-          println!("Updated piece table:");
-          for x in &self.pieces {
-              println!("  - {:?}", x);
-          }
-          println!("  orig:{}\n  apnd:{}", self.original.text.iter().collect::<String>(), self.append.text.iter().collect::<String>());
-          println!(" FENWICK {:?} len{}", self.length_sum, self.len());
-          self.print_prefixes();
-    }
-
-    //pub fn  print_prefixes(&self) {
-    //    println!("  PREFIXES : {:?}", self.pieces.iter().enumerate().map(|(i,_)| self.length_sum.prefix_sum(i)));
-    //}
-
-    //pub fn insert(&mut self, idx :usize, c :char) {
-    //    println!("OLD piece table:");
-    //    for x in &self.pieces {
-    //        println!("  - {:?}", x);
-    //    }
-    //    println!("  orig:{}\n  apnd:{}", self.original.text.iter().collect::<String>(), self.append.text.iter().collect::<String>());
-    //    println!("  FIND {}@ {:?}", idx, self.length_sum.find_prefix(idx));
-
-    //    if idx == 0 { // TODO how to get rid of this special case?
-    //        for i in 0..(self.pieces.len()) {
-    //            self.length_sum.sub(i,   self.pieces[i].len());
-    //            self.length_sum.add(i+1, self.pieces[i].len());
-    //        }
-
-    //        let append_idx = self.append.text.len();
-    //        self.append.text.push(c);
-    //        self.pieces.insert(0, Ref::Append(append_idx,1));
-    //        self.length_sum.add(0, 1);
-    //    } else {
-    //        match self.length_sum.find_prefix(idx) {
-    //            Ok(piece_idx) => { // Add to/after end of piece
-    //                match self.pieces[piece_idx] {
-    //                    Ref::Original(x, l) => {
-    //                        // Insert new piece
-    //                        println!("NEW PIECE");
-    //                        // update length_sum
-    //                        for i in (piece_idx+1)..(self.pieces.len()) {
-    //                            println!("SHIFT length_sum @{}", i); 
-    //                            // Shift pieces ahead
-    //                            self.length_sum.sub(i,   self.pieces[i].len());
-    //                            self.length_sum.add(i+1, self.pieces[i].len());
-    //                        }
-
-    //                        let append_idx = self.append.text.len();
-    //                        self.append.text.push(c);
-    //                        self.pieces.insert(piece_idx+1, Ref::Append(append_idx,1));
-    //                        
-    //                        self.length_sum.add(piece_idx+1, 1);
-    //                    },
-    //                    Ref::Append(x, l) => {
-    //                        println!("ADD@PIECE");
-    //                        self.append.text.push(c);
-    //                        self.pieces[piece_idx] = Ref::Append(x,l+1);
-    //                        self.length_sum.add(piece_idx, 1);
-    //                    }
-    //                }
-    //            },
-    //            Err(left_idx) => { // need to split piece
-    //                if left_idx > (self.pieces.len() -1) { panic!("Insert after end of buffer"); }
-    //                let length_before_piece = if left_idx == 0 { 0 }  else { self.length_sum.prefix_sum(left_idx-1 ) };
-    //                println!("SPLIT AT {} @{}-{}",left_idx, idx,length_before_piece);
-    //                let (before,after) = self.pieces[left_idx].split(idx-length_before_piece);
-    //                self.length_sum.sub(left_idx, after.len());
-
-    //                // update length sum after
-    //                for i in (left_idx+1)..(self.pieces.len()) {
-    //                    println!("SHIFT length_sum @{}",i);
-    //                    self.length_sum.sub(i,   self.pieces[i].len());
-    //                    self.length_sum.add(i+2, self.pieces[i].len());
-    //                }
-
-    //                // left_idx+1 and left_idx+2 is now empty
-    //                let append_idx = self.append.text.len();
-    //                self.append.text.push(c);
-    //                self.pieces[left_idx] = before;
-    //                self.pieces.insert(left_idx+1, after);
-    //                self.pieces.insert(left_idx+1, Ref::Append(append_idx, 1));
-    //                self.length_sum.add(left_idx+1, 1);
-    //                self.length_sum.add(left_idx+2, after.len());
-    //            },
-    //        }
-    //    }
-    //    println!("Updated piece table:");
-    //    for x in &self.pieces {
-    //        println!("  - {:?}", x);
-    //    }
-    //    println!("  orig:{}\n  apnd:{}", self.original.text.iter().collect::<String>(), self.append.text.iter().collect::<String>());
-    //}
+        self.record(actions, idx);
+    }
+
+    /// The character at index `idx`. This is `Snapshot::char_at` under
+    /// its old name, for code which holds the document mutably.
+    pub fn get(&mut self, idx :usize) -> char {
+        self.char_at(idx)
+    }
 
     pub fn remove(&mut self, idx: usize) {
-          println!("OLD piece table:");
-          for x in &self.pieces {
-              println!("  - {:?}", x);
-          }
-          println!("  orig:{}\n  apnd:{}", self.original.text.iter().collect::<String>(), self.append.text.iter().collect::<String>());
-          println!("  FIND {}@ {:?}", idx, self.length_sum.find_prefix(idx));
-      
         let actions = self.remove_actions(idx);
-        println!("ACTIONS: {:?}", actions);
         self.run(&actions);
-          println!("Updated piece table:");
-          for x in &self.pieces {
-              println!("  - {:?}", x);
-          }
-          println!("  orig:{}\n  apnd:{}", self.original.text.iter().collect::<String>(), self.append.text.iter().collect::<String>());
-          println!(" FENWICK {:?} len{}", self.length_sum, self.len());
-          self.print_prefixes();
+        self.record(actions, idx);
     }
 
-    pub fn get(&mut self, idx :usize) -> char {
-        println!("Document::get(&mut self, idx :usize) -> char");
-          println!(" FENWICK {:?} len{}", self.length_sum, self.len());
-          self.print_prefixes();
-        let left = self.length_sum.find_prefix_left(idx);
-        let prefix = if left > 0 { self.length_sum.prefix_sum(left - 1) }  else { 0 };
-        println!("Getting at {}, left {} prefix {}", idx, left, prefix);
-        let offset = idx - prefix;
-        println!("  (offset:{})",offset);
-        self.get_ref(&self.pieces[left])[offset]
+}
+
+fn encode_node(out :&mut Encoder, parent :usize, time :SystemTime, redo :Option<usize>, change :&Change) {
+    let millis = time.duration_since(UNIX_EPOCH).map(|since| since.as_millis() as u64).unwrap_or(0);
+    out.usize(parent);
+    out.u64(millis);
+    out.usize(redo.map_or(0, |redo| redo + 1));
+    out.usize(change.pos);
+    out.usize(change.ops.len());
+    for op in &change.ops {
+        match *op {
+            DocOp::Remove(idx, ref r) => { out.u8(0); out.usize(idx); encode_ref(out, r); },
+            DocOp::Insert(idx, ref r) => { out.u8(1); out.usize(idx); encode_ref(out, r); },
+            DocOp::Set(idx, ref a, ref b) => { out.u8(2); out.usize(idx); encode_ref(out, a); encode_ref(out, b); },
+        }
+    }
+}
+
+/// The text of `buffer` preceded by its length, as `Decoder::bytes` reads
+/// it back.
+fn encode_buffer(out :&mut Encoder, buffer :&Buffer) {
+    out.usize(buffer.len());
+    for segment in &buffer.segments {
+        out.bytes.extend_from_slice(segment.text().as_bytes());
+    }
+}
+
+fn encode_ref(out :&mut Encoder, r :&Ref) {
+    match *r {
+        Ref::Original(start, len) => { out.u8(0); out.usize(start); out.usize(len); },
+        Ref::Append(start, len) => { out.u8(1); out.usize(start); out.usize(len); },
+    }
+}
+
+impl Snapshot {
+    /// The segment of `r`, and its byte offset and length there.
+    fn buffer(&self, r :&Ref) -> (&Segment, usize, usize) {
+        let (buffer, idx, len) = match r {
+            Ref::Original(idx,len) => (&self.original, *idx, *len),
+            Ref::Append(idx,len) => (&self.append, *idx, *len),
+        };
+        let (segment, offset) = buffer.segment(idx);
+        (segment, offset, len)
+    }
+
+    fn get_ref(&self, r :&Ref) -> &str {
+        let (buffer, idx, len) = self.buffer(r);
+        &buffer.text()[idx..(idx+len)]
+    }
+
+    /// Number of line breaks within the piece `r`.
+    fn ref_lines(&self, r :&Ref) -> usize {
+        let (buffer, idx, len) = self.buffer(r);
+        buffer.count_range(idx..idx+len).lines
+    }
+
+    /// Number of characters in the piece `r`.
+    fn ref_chars(&self, r :&Ref) -> usize {
+        let (buffer, idx, len) = self.buffer(r);
+        buffer.count_range(idx..idx+len).chars
+    }
+
+    /// Byte offset within the piece `r` of its character `chars`.
+    fn byte_offset(&self, r :&Ref, chars :usize) -> usize {
+        let (buffer, idx, _) = self.buffer(r);
+        buffer.advance(idx, chars) - idx
+    }
+
+    fn weight(&self, r :&Ref) -> Weight {
+        Weight { bytes: r.len(), chars: self.ref_chars(r), lines: self.ref_lines(r) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.pieces.sum().chars
+    }
+
+    /// Number of lines, i.e. one more than the number of line breaks.
+    pub fn line_count(&self) -> usize {
+        self.pieces.sum().lines + 1
+    }
+
+    /// Character index of the first character on `line`.
+    pub fn line_start(&self, line :usize) -> usize {
+        if line == 0 { return 0; }
+        if line >= self.line_count() { panic!("Line after end of buffer"); }
+
+        // Find the piece containing the line break ending the previous line.
+        let (piece_idx, before) = self.pieces.find_line(line - 1);
+        let (buffer, start, _) = self.buffer(&self.pieces.get(piece_idx));
+        let newline = buffer.newline(start, line - 1 - before.lines);
+        before.chars + buffer.count_range(start..newline).chars + 1
+    }
+
+    /// Line number containing the character index `idx`.
+    pub fn line_of(&self, idx :usize) -> usize {
+        let (piece_idx, before) = self.pieces.find_char(idx);
+        if piece_idx >= self.pieces.len() { return before.lines; }
+        let piece = self.pieces.get(piece_idx);
+        let offset = self.byte_offset(&piece, idx - before.chars);
+        before.lines + self.ref_lines(&piece.split(offset).0)
     }
 
-    fn foo(n: u32) -> impl Iterator<Item = char> {
-        (0..n).map(|x| 'c')
+    /// Number of characters on `line`, not counting the line break.
+    pub fn line_len(&self, line :usize) -> usize {
+        let end = if line + 1 < self.line_count() { self.line_start(line + 1) - 1 } else { self.len() };
+        end - self.line_start(line)
     }
 
-    fn print_prefixes(&self) {
-        for i in 0..(self.pieces.len()) {
-            println!("piece i len{} cum{}", self.pieces[i].len(), self.length_sum.prefix_sum(i));
+    /// The pieces holding the characters in `range`, cut to fit.
+    fn refs(&self, range :Range<usize>) -> Vec<Ref> {
+        let mut refs = Vec::new();
+        if range.start >= range.end { return refs; }
+        let (piece_idx, before) = self.pieces.find_char(range.start);
+        let mut pos = before.chars;
+        for piece in self.pieces.iter_from(piece_idx) {
+            if pos >= range.end { break; }
+            let chars = self.ref_chars(piece);
+            let from = if range.start > pos { self.byte_offset(piece, range.start - pos) } else { 0 };
+            let to = if range.end < pos + chars { self.byte_offset(piece, range.end - pos) } else { piece.len() };
+            refs.push(piece.skip(from).split(to - from).0);
+            pos += chars;
         }
+        refs
+    }
+
+    /// Split the pieces in `refs` at the character `offset`. Returns the
+    /// index of the first piece after it.
+    fn split_refs(&self, refs :&mut Vec<Ref>, mut offset :usize) -> usize {
+        for i in 0..refs.len() {
+            if offset == 0 { return i; }
+            let chars = self.ref_chars(&refs[i]);
+            if offset < chars {
+                let (first, second) = refs[i].split(self.byte_offset(&refs[i], offset));
+                refs[i] = first;
+                refs.insert(i + 1, second);
+                return i + 1;
+            }
+            offset -= chars;
+        }
+        refs.len()
+    }
+
+    /// The text in `range`.
+    pub fn slice(&self, range :Range<usize>) -> String {
+        self.chunks(range).collect()
+    }
+
+    /// The text in `range`, as slices of the underlying buffers.
+    pub fn chunks<'a>(&'a self, range :Range<usize>) -> Chunks<'a> {
+        let (piece_idx, before) = self.pieces.find_char(range.start);
+        Chunks { doc: self, pieces: self.pieces.iter_from(piece_idx), pos: before.chars, range }
+    }
+
+    /// The characters of `line`, without the line break.
+    pub fn line<'a>(&'a self, line :usize) -> Chunks<'a> {
+        let start = self.line_start(line);
+        self.chunks(start..start + self.line_len(line))
+    }
+
+    /// Iterate over the lines in `lines`, each given as chunks of characters.
+    pub fn lines<'a>(&'a self, lines :Range<usize>) -> Lines<'a> {
+        Lines { doc: self, lines: lines.start..usize::min(lines.end, self.line_count()) }
+    }
+
+    /// Byte offset of the character index `idx`.
+    fn byte_of(&self, idx :usize) -> usize {
+        let (piece_idx, before) = self.pieces.find_char(idx);
+        if piece_idx >= self.pieces.len() { return before.bytes; }
+        before.bytes + self.byte_offset(&self.pieces.get(piece_idx), idx - before.chars)
+    }
+
+    /// Character index of the byte offset `idx`.
+    fn char_of(&self, idx :usize) -> usize {
+        let (piece_idx, before) = self.pieces.find_byte(idx);
+        if piece_idx >= self.pieces.len() { return before.chars; }
+        let (buffer, start, _) = self.buffer(&self.pieces.get(piece_idx));
+        before.chars + buffer.count_range(start..start + idx - before.bytes).chars
+    }
+
+    /// The piece containing the byte offset `idx`, and the offset where it
+    /// starts.
+    fn chunk_at_byte(&self, idx :usize) -> (&str, usize) {
+        let (piece_idx, before) = self.pieces.find_byte(idx);
+        if piece_idx >= self.pieces.len() { return ("", before.bytes); }
+        (self.get_ref(&self.pieces.get(piece_idx)), before.bytes)
+    }
+
+    /// Move `cursor` to the next or previous grapheme boundary, feeding it
+    /// pieces as it asks for them. Returns the boundary's character index.
+    fn grapheme_boundary(&self, mut cursor :GraphemeCursor, idx :usize, forward :bool) -> Option<usize> {
+        let (mut chunk, mut start) = self.chunk_at_byte(idx);
+        if !forward && idx == start && idx > 0 {
+            let (prev, prev_start) = self.chunk_at_byte(idx - 1);
+            chunk = prev;
+            start = prev_start;
+        }
+        loop {
+            let boundary = if forward { cursor.next_boundary(chunk, start) } else { cursor.prev_boundary(chunk, start) };
+            match boundary {
+                Ok(boundary) => return boundary.map(|b| self.char_of(b)),
+                Err(GraphemeIncomplete::NextChunk) => {
+                    start += chunk.len();
+                    chunk = self.chunk_at_byte(start).0;
+                },
+                Err(GraphemeIncomplete::PrevChunk) => {
+                    let (prev, prev_start) = self.chunk_at_byte(start - 1);
+                    chunk = prev;
+                    start = prev_start;
+                },
+                Err(GraphemeIncomplete::PreContext(end)) => {
+                    let (context, context_start) = self.chunk_at_byte(end - 1);
+                    cursor.provide_context(&context[..end - context_start], context_start);
+                },
+                Err(GraphemeIncomplete::InvalidOffset) => panic!("Grapheme boundary outside of buffer"),
+            }
+        }
+    }
+
+    /// The start of the grapheme cluster after the one at `idx`, or the
+    /// end of the document.
+    pub fn next_grapheme(&self, idx :usize) -> usize {
+        let len = self.pieces.sum().bytes;
+        let offset = self.byte_of(idx);
+        if offset >= len { return self.len(); }
+        self.grapheme_boundary(GraphemeCursor::new(offset, len, true), offset, true).unwrap_or_else(|| self.len())
+    }
+
+    /// The start of the grapheme cluster before `idx`, or 0.
+    pub fn prev_grapheme(&self, idx :usize) -> usize {
+        let offset = self.byte_of(idx);
+        if offset == 0 { return 0; }
+        let len = self.pieces.sum().bytes;
+        self.grapheme_boundary(GraphemeCursor::new(offset, len, true), offset, false).unwrap_or(0)
+    }
+
+    /// The grapheme clusters in `range`, whose ends should be boundaries.
+    pub fn graphemes<'a>(&'a self, range :Range<usize>) -> Graphemes<'a> {
+        Graphemes { doc: self, range }
+    }
+
+    /// The character at index `idx`.
+    pub fn char_at(&self, idx :usize) -> char {
+        let (piece_idx, before) = self.pieces.find_char(idx);
+        let piece = self.pieces.get(piece_idx);
+        let offset = self.byte_offset(&piece, idx - before.chars);
+        self.get_ref(&piece)[offset..].chars().next().expect("Index after end of buffer")
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f :&mut fmt::Formatter) -> fmt::Result {
+        for chunk in self.chunks(0..self.len()) {
+            f.write_str(chunk)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Document {
+    fn fmt(&self, f :&mut fmt::Formatter) -> fmt::Result {
+        self.text.fmt(f)
+    }
+}
+
+/// The document is read through its current snapshot.
+impl Deref for Document {
+    type Target = Snapshot;
+    fn deref(&self) -> &Snapshot {
+        &self.text
+    }
+}
+
+/// Iterator over the text in a range of a `Document`, one piece at a time.
+pub struct Chunks<'a> {
+    doc :&'a Snapshot,
+    pieces :treap::Iter<'a>,
+    /// Character index where the next piece starts.
+    pos :usize,
+    range :Range<usize>,
+}
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = &'a str;
+    fn next(&mut self) -> Option<&'a str> {
+        while self.pos < self.range.end {
+            let piece = self.pieces.next()?;
+            let chars = self.doc.ref_chars(piece);
+            let start = self.pos;
+            self.pos += chars;
+            let from = self.range.start.saturating_sub(start);
+            let to = usize::min(chars, self.range.end - start);
+            if from < to {
+                let text = self.doc.get_ref(piece);
+                return Some(&text[self.doc.byte_offset(piece, from)..self.doc.byte_offset(piece, to)]);
+            }
+        }
+        None
+    }
+}
+
+/// Iterator over the grapheme clusters in a range of a `Document`, with
+/// the character index where each starts.
+pub struct Graphemes<'a> {
+    doc :&'a Snapshot,
+    range :Range<usize>,
+}
+
+impl<'a> Iterator for Graphemes<'a> {
+    type Item = (usize, String);
+    fn next(&mut self) -> Option<(usize, String)> {
+        if self.range.start >= self.range.end { return None; }
+        let start = self.range.start;
+        self.range.start = usize::min(self.range.end, self.doc.next_grapheme(start));
+        Some((start, self.doc.slice(start..self.range.start)))
+    }
+}
+
+/// Iterator over a range of lines in a `Document`.
+pub struct Lines<'a> {
+    doc :&'a Snapshot,
+    lines :Range<usize>,
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = Chunks<'a>;
+    fn next(&mut self) -> Option<Chunks<'a>> {
+        let line = self.lines.next()?;
+        Some(self.doc.line(line))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Document;
+    use super::{DocOp, Document, Edit, Gravity, Segment, BLOCK, SEGMENT};
+    use undofile::{Decoder, Encoder};
+    use std::cell::RefCell;
+    use std::ops::Range;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use rand::{thread_rng, Rng};
+    use std::{env, fs, process};
 
     #[test]
     fn test_doc_immutable() {
@@ -485,90 +1629,571 @@ mod tests {
         println!("doc:{}",doc.to_string());
         assert_eq!(doc.len(), 10);
     }
-}
 
+    #[test]
+    fn test_undo_redo() {
+        let mut doc = Document::new("hallo".to_string());
+        assert_eq!(doc.undo(), None);
+
+        doc.insert(2,'x');
+        doc.insert(3,'y');
+        doc.remove(0);
+        assert_eq!(doc.to_string(), "axyllo");
+
+        assert_eq!(doc.undo(), Some(0));
+        assert_eq!(doc.to_string(), "haxyllo");
+        assert_eq!(doc.undo(), Some(3));
+        assert_eq!(doc.to_string(), "haxllo");
+        assert_eq!(doc.undo(), Some(2));
+        assert_eq!(doc.to_string(), "hallo");
+        assert_eq!(doc.undo(), None);
+
+        assert_eq!(doc.redo(), Some(2));
+        assert_eq!(doc.to_string(), "haxllo");
+
+        // A new edit starts a new branch, with nothing to redo.
+        doc.insert(0,'z');
+        assert_eq!(doc.redo(), None);
+        assert_eq!(doc.to_string(), "zhaxllo");
+        assert_eq!(doc.undo(), Some(0));
+        assert_eq!(doc.undo(), Some(2));
+        assert_eq!(doc.to_string(), "hallo");
+    }
+
+    #[test]
+    fn test_undo_tree() {
+        let mut doc = Document::new("abc".to_string());
+        doc.insert(3, 'd');
+        doc.insert(4, 'e');
+        doc.undo();
+        doc.undo();
+        doc.remove(0);
+        doc.undo();
+        doc.insert(0, 'x');
+        assert_eq!(doc.redo(), None);
+        assert_eq!(doc.undo(), Some(0));
+        assert_eq!(doc.redo(), Some(0));
+
+        // States are visited in the order they were made, across branches.
+        let states = ["abc", "abcd", "abcde", "bc", "xabc"];
+        for state in (0..4).rev() {
+            assert!(doc.earlier(1).is_some());
+            assert_eq!(doc.to_string(), states[state]);
+        }
+        assert_eq!(doc.earlier(1), None);
+        assert_eq!(doc.later(2), Some(4));
+        assert_eq!(doc.to_string(), "abcde");
+        assert_eq!(doc.undo(), Some(4));
+        assert_eq!(doc.later(10), Some(0));
+        assert_eq!(doc.to_string(), "xabc");
+        assert_eq!(doc.later(1), None);
+        assert_eq!(doc.undo_leaves().iter().map(|leaf| (leaf.number, leaf.changes)).collect::<Vec<_>>(),
+                   vec![(2, 2), (3, 1), (4, 1)]);
+
+        // Changes made a minute apart.
+        let start = doc.undo_tree[0].time;
+        for (number, node) in doc.undo_tree.iter_mut().enumerate() {
+            node.time = start + Duration::from_secs(60 * number as u64);
+        }
+        assert!(doc.earlier_by(Duration::from_secs(90)).is_some());
+        assert_eq!(doc.to_string(), "abcde");
+        assert!(doc.earlier_by(Duration::from_secs(600)).is_some());
+        assert_eq!(doc.to_string(), "abc");
+        assert!(doc.later_by(Duration::from_secs(150)).is_some());
+        assert_eq!(doc.to_string(), "abcde");
+        assert!(doc.later_by(Duration::from_secs(3600)).is_some());
+        assert_eq!(doc.to_string(), "xabc");
+        assert_eq!(doc.later_by(Duration::from_secs(3600)), None);
+    }
+
+    #[test]
+    fn test_undo_tree_random() {
+        let mut rng = thread_rng();
+        for _ in 0..100 {
+            let initial = random_text(&mut rng, 10);
+            let mut doc = Document::new(initial.clone());
+            // The text of every state, by number.
+            let mut states = vec![initial];
+            for _ in 0..40 {
+                match rng.gen_range(0, 6) {
+                    0 => { doc.undo(); },
+                    1 => { doc.redo(); },
+                    2 => { doc.earlier(rng.gen_range(1, 4)); },
+                    3 => { doc.later(rng.gen_range(1, 4)); },
+                    4 => { doc.travel(rng.gen_range(0, states.len())); },
+                    _ => {
+                        let start = rng.gen_range(0, doc.len() + 1);
+                        let end = rng.gen_range(start, doc.len() + 1);
+                        if rng.gen() {
+                            doc.remove_range(start..end);
+                        } else {
+                            doc.insert_str(start, &random_text(&mut rng, 4));
+                        }
+                        if doc.state == states.len() {
+                            states.push(doc.to_string());
+                        }
+                    },
+                }
+                assert_eq!(doc.to_string(), states[doc.state]);
+            }
+            assert!(doc.replays());
+        }
+    }
+
+    #[test]
+    fn test_history() {
+        let mut doc = Document::new("one two".to_string());
+        doc.remove_range(0..4);
+        doc.insert_str(3, "!");
+        doc.undo();
+        let decode = |doc :&Document| {
+            let mut out = Encoder::default();
+            doc.encode_history(&mut out);
+            Document::decode_history(&mut Decoder::new(&out.bytes)).map(|doc| (doc.to_string(), doc.state))
+        };
+        assert_eq!(decode(&doc), Ok(("two".to_string(), 1)));
+        // Changes which do not fit the pieces are refused, even with a
+        // good checksum.
+        let invalid = Err("Invalid undo tree in undo file".to_string());
+        let piece = doc.pieces.get(0);
+        doc.undo_tree[2].change.ops[0] = DocOp::Set(1, piece, piece);
+        assert_eq!(decode(&doc), invalid);
+        doc.undo_tree[2].change.ops[0] = DocOp::Remove(0, piece.skip(1));
+        assert_eq!(decode(&doc), invalid);
+        doc.undo_tree[2].change.ops.clear();
+        doc.undo_tree[2].change.pos = 10;
+        assert_eq!(decode(&doc), invalid);
+    }
+
+    #[test]
+    fn test_range_ops() {
+        let mut doc = Document::new("hallo".to_string());
+        doc.insert_str(2, "xyz");
+        assert_eq!(doc.to_string(), "haxyzllo");
+        doc.insert_str(5, "w");
+        assert_eq!(doc.to_string(), "haxyzwllo");
+        assert_eq!(doc.pieces.len(), 3);
+
+        doc.remove_range(1..8);
+        assert_eq!(doc.to_string(), "ho");
+        assert_eq!(doc.pieces.len(), 2);
+        doc.remove_range(0..2);
+        assert_eq!(doc.to_string(), "");
+        assert_eq!(doc.pieces.len(), 0);
+
+        doc.undo();
+        doc.undo();
+        assert_eq!(doc.to_string(), "haxyzwllo");
+    }
+
+    #[test]
+    fn test_range_ops_random() {
+        let mut rng = thread_rng();
+        for _ in 0..300 {
+            random_edits(&mut rng);
+        }
+    }
+
+    fn random_text<TRng: Rng>(rng: &mut TRng, max_len :usize) -> String {
+        const ALPHABET :&[char] = &['a', 'b', ' ', '\n', 'ö', '€', '𝄞'];
+        let len = rng.gen_range(0, max_len + 1);
+        (0..len).map(|_| ALPHABET[rng.gen_range(0, ALPHABET.len())]).collect()
+    }
+
+    fn random_edits<TRng: Rng>(rng: &mut TRng) {
+        let initial = random_text(rng, 20);
+        let mut doc = Document::new(initial.clone());
+        let mut model :Vec<char> = initial.chars().collect();
+
+        for _ in 0..40 {
+            if model.is_empty() || rng.gen() {
+                let idx = rng.gen_range(0, model.len() + 1);
+                let text = random_text(rng, 6);
+                doc.insert_str(idx, &text);
+                for (i,c) in text.chars().enumerate() {
+                    model.insert(idx + i, c);
+                }
+            } else {
+                let start = rng.gen_range(0, model.len());
+                let end = rng.gen_range(start, model.len() + 1);
+                doc.remove_range(start..end);
+                model.drain(start..end);
+            }
+            assert_eq!(doc.len(), model.len());
+            assert_eq!(doc.to_string(), model.iter().collect::<String>());
+            assert_eq!(doc.pieces.sum().bytes, doc.to_string().len());
+            check_lines(&doc, &model);
+            let start = rng.gen_range(0, model.len() + 1);
+            let end = rng.gen_range(start, model.len() + 1);
+            assert_eq!(doc.slice(start..end), model[start..end].iter().collect::<String>());
+        }
+
+        while doc.undo().is_some() {}
+        assert_eq!(doc.to_string(), initial);
+    }
+
+    fn check_lines(doc :&Document, model :&[char]) {
+        let mut line_starts = vec![0];
+        for (i,c) in model.iter().enumerate() {
+            if *c == '\n' { line_starts.push(i+1); }
+        }
+        assert_eq!(doc.line_count(), line_starts.len());
+        for (line,start) in line_starts.iter().enumerate() {
+            assert_eq!(doc.line_start(line), *start);
+            let end = line_starts.get(line+1).map(|x| x-1).unwrap_or(model.len());
+            assert_eq!(doc.line_len(line), end - start);
+        }
+        for idx in 0..(model.len()+1) {
+            let line = line_starts.iter().filter(|s| **s <= idx).count() - 1;
+            assert_eq!(doc.line_of(idx), line);
+        }
+        let text = model.iter().collect::<String>();
+        let lines = doc.lines(0..doc.line_count() + 1)
+            .map(|chunks| chunks.collect::<String>())
+            .collect::<Vec<_>>();
+        assert_eq!(lines, text.split('\n').collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_lines() {
+        let mut doc = Document::new("ab\ncd\n\nefg".to_string());
+        assert_eq!(doc.line_count(), 4);
+        assert_eq!(doc.line_start(1), 3);
+        assert_eq!(doc.line_start(3), 7);
+        assert_eq!(doc.line_len(2), 0);
+        assert_eq!(doc.line_of(2), 0);
+        assert_eq!(doc.line_of(3), 1);
+        assert_eq!(doc.line_of(10), 3);
+
+        doc.insert_str(4, "x\ny");
+        assert_eq!(doc.to_string(), "ab\ncx\nyd\n\nefg");
+        assert_eq!(doc.line_count(), 5);
+        assert_eq!(doc.line_start(2), 6);
+        assert_eq!(doc.line_len(2), 2);
+        assert_eq!(doc.line_of(12), 4);
+    }
+
+    #[test]
+    fn test_utf8() {
+        let mut doc = Document::new("aö€\n𝄞b".to_string());
+        assert_eq!((doc.len(), doc.pieces.sum().bytes), (6, 12));
+        assert_eq!(doc.get(2), '€');
+        assert_eq!(doc.line_start(1), 4);
+        assert_eq!(doc.line_of(5), 1);
+        doc.insert_str(2, "ü\n");
+        doc.remove(6);
+        assert_eq!(doc.to_string(), "aöü\n€\nb");
+        assert_eq!(doc.slice(2..5), "ü\n€");
+        assert_eq!(doc.chunks(1..7).collect::<Vec<_>>(), vec!["ö", "ü\n", "€\n", "b"]);
+        assert_eq!((doc.line_start(2), doc.line_len(1)), (6, 1));
+    }
+
+    #[test]
+    fn test_graphemes() {
+        // e + combining acute, a flag, a family emoji, and CR LF.
+        let text = "ae\u{301}b🇳🇴👨\u{200d}👩\u{200d}👧\r\nx";
+        let mut doc = Document::new(text.to_string());
+        // Split the pieces inside the clusters.
+        doc.insert_str(2, "");
+        doc.insert(5, 'y');
+        doc.remove(5);
+        doc.insert(7, 'y');
+        doc.remove(7);
+        assert_eq!(doc.to_string(), text);
+        let starts = [0, 1, 3, 4, 6, 11, 13, 14];
+        for pair in starts.windows(2) {
+            assert_eq!(doc.next_grapheme(pair[0]), pair[1]);
+            assert_eq!(doc.prev_grapheme(pair[1]), pair[0]);
+        }
+        assert_eq!(doc.next_grapheme(2), 3);
+        assert_eq!(doc.prev_grapheme(2), 1);
+        assert_eq!(doc.prev_grapheme(0), 0);
+        assert_eq!(doc.next_grapheme(14), 14);
+        let graphemes = doc.graphemes(1..6).collect::<Vec<_>>();
+        assert_eq!(graphemes, vec![(1, "e\u{301}".to_string()), (3, "b".to_string()), (4, "🇳🇴".to_string())]);
+        let widths = doc.graphemes(0..14).map(|(_, g)| super::width(&g)).collect::<Vec<_>>();
+        assert_eq!(widths, vec![1, 1, 1, 2, 2, 1, 1]);
+        assert_eq!(super::width("漢"), 2);
+    }
+
+    #[test]
+    fn test_open() {
+        let path = env::temp_dir().join(format!("version6-open-{}.txt", process::id()));
+        let text = "first line\nsecond ö€𝄞 line\n".repeat(20);
+        fs::write(&path, &text).unwrap();
+        let mut doc = Document::open_in_chunks(&path, 3).unwrap();
+        assert!(doc.len() <= 3);
+        doc.insert_str(0, "x");
+        while doc.loading() {
+            doc.poll().unwrap();
+        }
+        assert_eq!(doc.to_string(), format!("x{}", text));
+        assert_eq!(doc.line_count(), 41);
+        assert_eq!(doc.line_start(3), 1 + 11 + 16 + 11);
+        doc.undo();
+        assert_eq!(doc.to_string(), text);
+        fs::write(&path, "").unwrap();
+        assert_eq!(doc.to_string(), text);
+
+        fs::write(&path, b"ok\n\xff").unwrap();
+        let mut doc = Document::open_in_chunks(&path, 2).unwrap();
+        while doc.loading() {
+            if let Err(e) = doc.poll() {
+                assert_eq!(e, "Invalid UTF-8 at byte 3");
+            }
+        }
+        assert_eq!(doc.to_string(), "ok");
+        assert!(Document::open_in_chunks(&path, 10).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_index() {
+        let mut rng = thread_rng();
+        let alphabet = ['a', '\n', 'ö', '€', '𝄞'];
+        let mut segment = Segment::new(String::new());
+        let mut text = String::new();
+        while text.len() < 5 * BLOCK {
+            let part = (0..rng.gen_range(0, 2000)).map(|_| alphabet[rng.gen_range(0, alphabet.len())]).collect::<String>();
+            segment.push_str(&part);
+            text.push_str(&part);
+        }
+        assert_eq!(segment.text(), text);
+        assert!(segment.checkpoints.windows(2).all(|w| w[1].byte / BLOCK == w[0].byte / BLOCK + 1));
+        let mut lines = 0;
+        for (chars, (byte, c)) in text.char_indices().enumerate() {
+            if chars % 10 == 0 {
+                let at = segment.count(byte);
+                assert_eq!((at.chars, at.lines), (chars, lines));
+                assert_eq!(segment.advance(0, chars), byte);
+            }
+            if c == '\n' {
+                assert_eq!(segment.newline(0, lines), byte);
+                lines += 1;
+            }
+        }
+        let bounds = text.char_indices().map(|(i,_)| i).collect::<Vec<_>>();
+        for _ in 0..100 {
+            let (a, b) = (bounds[rng.gen_range(0, bounds.len())], bounds[rng.gen_range(0, bounds.len())]);
+            let range = usize::min(a, b)..usize::max(a, b);
+            let at = segment.count_range(range.clone());
+            assert_eq!((at.chars, at.lines), (text[range.clone()].chars().count(), text[range].matches('\n').count()));
+        }
+    }
+
+    #[test]
+    fn test_transaction() {
+        let mut doc = Document::new("ab".to_string());
+        doc.begin_transaction();
+        doc.insert(1,'x');
+        doc.insert(2,'y');
+        doc.begin_transaction();
+        doc.remove(0);
+        doc.commit_transaction();
+        doc.insert(3,'z');
+        doc.group_boundary();
+        doc.insert(4,'w');
+        doc.commit_transaction();
+        assert_eq!(doc.to_string(), "xybzw");
+
+        assert_eq!(doc.undo(), Some(4));
+        assert_eq!(doc.to_string(), "xybz");
+        assert_eq!(doc.undo(), Some(0));
+        assert_eq!(doc.to_string(), "ab");
+        assert_eq!(doc.undo(), None);
+
+        assert_eq!(doc.redo(), Some(0));
+        assert_eq!(doc.to_string(), "xybz");
+    }
+
+    #[test]
+    fn test_anchors() {
+        let mut doc = Document::new("one two".to_string());
+        let left = doc.add_anchor(4, Gravity::Left);
+        let right = doc.add_anchor(4, Gravity::Right);
+        doc.insert_str(4, "and ");
+        assert_eq!((doc.anchor(left), doc.anchor(right)), (4, 8));
+        doc.insert_str(9, "x");
+        assert_eq!((doc.anchor(left), doc.anchor(right)), (4, 8));
+        doc.remove_range(2..6);
+        assert_eq!(doc.to_string(), "ond txwo");
+        assert_eq!((doc.anchor(left), doc.anchor(right)), (2, 4));
+        doc.insert_str(2, "--");
+        assert_eq!((doc.anchor(left), doc.anchor(right)), (2, 6));
+        doc.undo();
+        doc.undo();
+        assert_eq!((doc.anchor(left), doc.anchor(right)), (4, 8));
+
+        doc.set_anchor(right, 1);
+        doc.remove_anchor(left);
+        let end = doc.add_anchor(100, Gravity::Right);
+        assert_eq!(end, left);
+        assert_eq!(doc.anchor(end), doc.len());
+        doc.insert_str(doc.len(), "!");
+        assert_eq!((doc.anchor(right), doc.anchor(end)), (1, doc.len()));
+    }
+
+    #[test]
+    fn test_snapshot() {
+        let mut doc = Document::new("one\ntwo".to_string());
+        doc.insert_str(3, "!");
+        let snapshot = doc.snapshot();
+        doc.insert_str(0, "zero\n");
+        doc.remove_range(5..9);
+        let reader = thread::spawn(move || {
+            let lines = snapshot.lines(0..snapshot.line_count()).map(|l| l.collect::<String>()).collect::<Vec<_>>();
+            (snapshot.to_string(), snapshot.char_at(3), lines)
+        });
+        doc.undo();
+        doc.insert_str(2, "x");
+        assert_eq!(reader.join().unwrap(), ("one!\ntwo".to_string(), '!', vec!["one!".to_string(), "two".to_string()]));
+        assert_eq!(doc.to_string(), "zexro\none!\ntwo");
+    }
+
+    #[test]
+    fn test_segments() {
+        let mut doc = Document::new(String::new());
+        doc.insert_str(0, &"a".repeat(SEGMENT - 1));
+        // A piece does not go past the end of its segment.
+        doc.insert_str(SEGMENT - 1, "bc");
+        assert_eq!(doc.append.segments.len(), 2);
+        assert_eq!(doc.pieces.len(), 2);
+        doc.insert_str(SEGMENT + 1, "d");
+        assert_eq!(doc.pieces.len(), 2);
+        // Nor is a segment added to while a snapshot shares it.
+        let snapshot = doc.snapshot();
+        doc.insert_str(SEGMENT + 2, "e");
+        assert_eq!(doc.append.segments.len(), 3);
+        assert!(snapshot.append.segments.iter().zip(&doc.append.segments).all(|(a, b)| Arc::ptr_eq(a, b)));
+        assert_eq!(doc.pieces.len(), 3);
+        drop(snapshot);
+        doc.insert_str(SEGMENT + 3, "f");
+        assert_eq!(doc.append.segments.len(), 3);
+        assert_eq!(doc.pieces.len(), 3);
+        assert_eq!(doc.slice(SEGMENT - 2..SEGMENT + 4), "abcdef");
+    }
+
+    /// An observer which keeps the edits it is told about.
+    fn record_edits(doc :&mut Document) -> Rc<RefCell<Vec<Edit>>> {
+        let edits = Rc::new(RefCell::new(Vec::new()));
+        let sink = edits.clone();
+        doc.subscribe(Box::new(move |edit :&Edit| sink.borrow_mut().push(edit.clone())));
+        edits
+    }
+
+    #[test]
+    fn test_observers() {
+        let mut doc = Document::new("one two".to_string());
+        let edits = record_edits(&mut doc);
+        doc.insert_str(4, "and\n");
+        doc.remove_range(2..9);
+        doc.undo();
+        let edit = |removed :Range<usize>, inserted :&str, line_delta| Edit { removed, inserted: inserted.to_string(), line_delta };
+        assert_eq!(*edits.borrow(), vec![edit(4..4, "and\n", 1), edit(2..9, "", -1), edit(2..2, "e and\nt", 1)]);
+
+        let id = doc.subscribe(Box::new(|_ :&Edit| {}));
+        assert!(doc.unsubscribe(id).is_some());
+        assert!(doc.unsubscribe(id).is_none());
+    }
 
+    #[test]
+    fn test_observers_random() {
+        let mut rng = thread_rng();
+        for _ in 0..100 {
+            let initial = random_text(&mut rng, 20);
+            let mut doc = Document::new(initial.clone());
+            let edits = record_edits(&mut doc);
+            for _ in 0..30 {
+                let len = doc.len();
+                match rng.gen_range(0, 4) {
+                    0 => { doc.undo(); },
+                    1 => { doc.redo(); },
+                    2 if len > 0 => {
+                        let start = rng.gen_range(0, len);
+                        doc.remove_range(start..rng.gen_range(start, len + 1));
+                    },
+                    _ => doc.insert_str(rng.gen_range(0, len + 1), &random_text(&mut rng, 6)),
+                }
+            }
+            let mut model :Vec<char> = initial.chars().collect();
+            let mut lines = model.iter().filter(|c| **c == '\n').count() as isize;
+            for edit in edits.borrow().iter() {
+                model.splice(edit.removed.clone(), edit.inserted.chars());
+                let count = model.iter().filter(|c| **c == '\n').count() as isize;
+                assert_eq!(count - lines, edit.line_delta);
+                lines = count;
+            }
+            assert_eq!(model.iter().collect::<String>(), doc.to_string());
+        }
+    }
 
-// // https://stackoverflow.com/questions/16793550/binary-indexed-tree-how-to-find-index-with-given-cumulative-frequency
-// fn fw_find(sums :&[usize], mut freq :usize) -> Option<usize> {
-//     let mut idx = 0;
-//     let mut bitmask = (sums.len() as f64).log(2.0) as usize;
-// 
-//     while bitmask != 0 {
-//         let t_idx = idx + bitmask; // midpoint
-//         bitmask >>= 1; // halve interval
-// 
-//         if t_idx > sums.len() {
-//             continue;
-//         }
-// 
-//         if freq == sums[t_idx] {
-//             return Some(t_idx);
-//         } else if freq < sums[t_idx] {
-//             idx = t_idx;
-//             freq -= sums[t_idx];
-//         }
-//     }
-//     Some(idx)
-// }
-
-// 
-// pub struct Treap(Option<Node>);
-// impl Treap {
-//     pub fn new() -> Self { Treap(None) }
-//     pub fn add(&mut self, doc: &Document, pos :usize, value :Ref) {
-//         let (first,last) = split(root, pos, 0);
-//         let new_node = Node::new(doc, value);
-//         self.0 = first.merge(new_node).merge(last)
-//     }
-// 
-//     pub fn remove(&mut self, doc :&Document, pos :usize) -> Option<()> {
-//         let (first,second) = (self.0).take()?.split(pos, 0);
-//         let (_, last )     = second.split(1, 0);
-//         self.0 = first.merge(last);
-//         Some(())
-//     }
-// }
-// 
-// pub struct Node {
-//     priority :usize,
-//     value :Ref,
-//     length_sum :usize,
-//     line_sum :usize,
-//     left  :Option<Box<Node>>,
-//     right :Option<Box<Node>>,
-// }
-// 
-// fn rand() -> usize {
-//     unimplemented!()
-// }
-// 
-// impl Node {
-//     pub fn new(doc :&Document, value :Ref) -> Self {
-//         let length = doc.get_ref(&value).len();
-//         let lines  = doc.get_ref(&value).chars().filter(|c| *c == '\n').count();
-// 
-//         Node {
-//             priority: rand(),
-//             value: value,
-//             length_sum: length,
-//             line_sum: lines,
-//             left: None,
-//             right: None,
-//         }
-//     }
-// 
-//     pub fn merge(self, other: Node) -> Node {
-//         if self.priority > other.priority {
-//             self.right = self.right.map(|n| Box::new(n.merge(other)));
-//             self.update();
-//             self
-//         } else {
-//             other.left = other.left.map(|n| Box::new(self.merge(*n)));
-//             other.update();
-//             other
-//         }
-//     }
-// 
-//     pub fn update(&mut self) {}
-// }
+    /// Position of a model anchor: after or before the character it sticks
+    /// to, or where that character was removed.
+    fn model_anchor(model :&[usize], gravity :Gravity, stuck :Option<usize>, removed_at :usize) -> usize {
+        match (stuck, gravity) {
+            (None, Gravity::Left) => 0,
+            (None, Gravity::Right) => model.len(),
+            (Some(id), _) => match model.iter().position(|c| *c == id) {
+                Some(i) if gravity == Gravity::Left => i + 1,
+                Some(i) => i,
+                None => removed_at,
+            },
+        }
+    }
+
+    #[test]
+    fn test_anchors_random() {
+        let mut rng = thread_rng();
+        for _ in 0..100 {
+            let initial = random_text(&mut rng, 20);
+            let mut doc = Document::new(initial.clone());
+            // Each character in the model is identified by a number.
+            let mut model :Vec<usize> = (0..initial.chars().count()).collect();
+            let mut next_id = model.len();
+            let mut anchors = Vec::new();
+            for _ in 0..6 {
+                let pos = rng.gen_range(0, model.len() + 1);
+                let gravity = if rng.gen() { Gravity::Left } else { Gravity::Right };
+                let stuck = match gravity {
+                    Gravity::Left => pos.checked_sub(1),
+                    Gravity::Right => if pos < model.len() { Some(pos) } else { None },
+                }.map(|i| model[i]);
+                anchors.push((doc.add_anchor(pos, gravity), gravity, stuck, pos));
+            }
+            let initial_pos = anchors.iter().map(|a| doc.anchor(a.0)).collect::<Vec<_>>();
+
+            for _ in 0..30 {
+                if model.is_empty() || rng.gen() {
+                    let idx = rng.gen_range(0, model.len() + 1);
+                    let text = random_text(&mut rng, 6);
+                    let n = text.chars().count();
+                    for anchor in anchors.iter_mut() {
+                        if anchor.3 > idx { anchor.3 += n; }
+                    }
+                    doc.insert_str(idx, &text);
+                    model.splice(idx..idx, next_id..next_id + n);
+                    next_id += n;
+                } else {
+                    let start = rng.gen_range(0, model.len());
+                    let end = rng.gen_range(start, model.len() + 1);
+                    for anchor in anchors.iter_mut() {
+                        let at = anchor.2.and_then(|id| model.iter().position(|c| *c == id));
+                        anchor.3 = match at {
+                            Some(i) if start <= i && i < end => start,
+                            _ if anchor.3 >= end => anchor.3 - (end - start),
+                            _ => usize::min(anchor.3, start),
+                        };
+                    }
+                    doc.remove_range(start..end);
+                    model.drain(start..end);
+                }
+                for &(id, gravity, stuck, removed_at) in &anchors {
+                    assert_eq!(doc.anchor(id), model_anchor(&model, gravity, stuck, removed_at));
+                }
+            }
+
+            while doc.undo().is_some() {}
+            assert_eq!(anchors.iter().map(|a| doc.anchor(a.0)).collect::<Vec<_>>(), initial_pos);
+        }
+    }
+}
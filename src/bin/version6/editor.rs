@@ -1,20 +1,72 @@
-use document::Document;
-use gfx_glyph;
-use glutin::WindowEvent;
+use command::{self, Address, Command, Substitute, Travel};
+use document::{self, AnchorId, Document, Gravity, Snapshot};
+use getline::GetLine;
+use input::{InputEvent, Key, Modifiers};
+use motion::{self, Motion};
+use normal::{Action, InsertAt, NormalCommand, Operator, Pending, ScrollTo};
+use options::Options;
+use register::{Register, Registers, SystemClipboard};
 use renderer::TextCommand;
+use regex;
+use search;
+use substitute::Substitution;
+use undofile;
+use unicode_segmentation::UnicodeSegmentation;
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Eq)]
 pub enum Mode {
     Normal,
     Insert,
+    Command,
+    Visual,
+    VisualLine,
+    /// Typing a search pattern after `/` or `?`.
+    Search { backward :bool },
+    /// Asking whether to make each replacement of `:s` with the `c` flag.
+    Confirm,
 }
 
 pub struct Editor {
     document: Document,
+    /// The first line shown in the window.
     view_line: usize,
+    /// The number of lines that fit in the window, as of the last `resize`.
+    view_height: usize,
     cursor_pos: usize,
     mode: Mode,
     unsaved: bool,
+    /// The state of the undo tree in which the text was last read or
+    /// written, to tell whether undo and redo lead back to it.
+    saved_state: usize,
+    /// Set when the file could not be read, so that writing the document
+    /// back to it would lose its contents.
+    incomplete: bool,
+    path: Option<PathBuf>,
+    command_line: GetLine,
+    /// Message shown in the status line until the next key press.
+    message: Option<String>,
+    options: Options,
+    quit: bool,
+    pending: Pending,
+    registers: Registers,
+    /// The end of the selection opposite to the cursor in Visual modes.
+    visual_anchor: usize,
+    /// The pattern and direction of the last search.
+    last_search: Option<(String, bool)>,
+    /// The pattern highlighted by `render` with the `ignorecase` and
+    /// `smartcase` settings it was compiled with, and its compiled form.
+    highlight: Option<((String, bool, bool), Option<regex::Regex>)>,
+    /// Cursor position and view line to return to if a search is cancelled.
+    search_origin: (usize, usize),
+    /// The `:s` command waiting for confirmation in Confirm mode.
+    substitution: Option<Substitution>,
+    /// Anchors for the marks `a` to `z`.
+    marks: [Option<AnchorId>; 26],
 }
 
 use renderer::*;
@@ -23,30 +75,488 @@ impl Editor {
         Editor {
             document: Document::empty(),
             view_line: 0,
+            view_height: 20,
             cursor_pos: 0,
             mode: Mode::Normal,
             unsaved: false,
+            saved_state: 0,
+            incomplete: false,
+            path: None,
+            command_line: GetLine::new(),
+            message: None,
+            options: Options::default(),
+            quit: false,
+            pending: Pending::new(),
+            registers: Registers::new(Box::new(SystemClipboard::default())),
+            visual_anchor: 0,
+            last_search: None,
+            highlight: None,
+            search_origin: (0, 0),
+            substitution: None,
+            marks: [None; 26],
         }
     }
 
+    /// Open `path` for editing. A file that does not exist yet is created
+    /// on the first write.
+    pub fn open(path :PathBuf) -> Self {
+        let mut editor = Editor::new();
+        editor.load(path);
+        editor
+    }
+
+    /// Replace the document with the contents of `path`.
+    fn load(&mut self, path :PathBuf) {
+        self.incomplete = false;
+        let history = if self.options.undofile { undofile::load(&real_path(&path)) } else { Ok(None) };
+        self.document = match history {
+            Ok(Some(document)) => document,
+            Ok(None) => self.open_document(&path),
+            Err(e) => {
+                let document = self.open_document(&path);
+                self.message = Some(format!("Undo history not restored: {}", e));
+                document
+            },
+        };
+        self.path = Some(path);
+        self.marks = [None; 26];
+        self.cursor_pos = 0;
+        self.view_line = 0;
+        self.unsaved = false;
+        self.saved_state = self.document.state();
+    }
+
+    /// Open `path` without its undo history, reporting errors in the status
+    /// line.
+    fn open_document(&mut self, path :&Path) -> Document {
+        match Document::open(path) {
+            Ok(document) => document,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                self.message = Some(format!("\"{}\" [New File]", path.display()));
+                Document::empty()
+            },
+            Err(e) => {
+                self.message = Some(format!("Error reading \"{}\": {}", path.display(), e));
+                self.incomplete = true;
+                Document::empty()
+            },
+        }
+    }
+
+    /// Take in more of a file being loaded. Returns whether the editor
+    /// needs to be drawn again.
+    pub fn poll(&mut self) -> bool {
+        match self.document.poll() {
+            Ok(grew) => grew,
+            Err(e) => {
+                self.message = Some(format!("Error reading \"{}\": {}",
+                    self.path.as_ref().map(|p| p.display().to_string()).unwrap_or_default(), e));
+                self.incomplete = true;
+                true
+            },
+        }
+    }
+
+    /// True while a file is still being loaded, so that `poll` should be
+    /// called without waiting for input.
+    pub fn loading(&self) -> bool {
+        self.document.loading()
+    }
+
+    /// True when the user has asked to close the editor.
+    pub fn quit_requested(&self) -> bool {
+        self.quit
+    }
+
+    /// Save the document to `path`, or to its own file if `path` is `None`,
+    /// reporting the outcome in the status line. Returns true on success.
+    pub fn write(&mut self, path :Option<PathBuf>) -> bool {
+        let path = match path.or_else(|| self.path.clone()) {
+            Some(path) => path,
+            None => {
+                self.message = Some("No file name".to_string());
+                return false;
+            }
+        };
+        if self.document.loading() {
+            self.message = Some(format!("\"{}\" is still being read; not writing it yet",
+                self.path.as_ref().unwrap_or(&path).display()));
+            return false;
+        }
+        if self.incomplete && self.path.as_ref() == Some(&path) {
+            self.message = Some(format!("\"{}\" was not read completely; not overwriting it", path.display()));
+            return false;
+        }
+        // Taking a snapshot is cheap, and leaves the document free to change
+        // while the file is written.
+        match write_atomic(&path, &self.document.snapshot()) {
+            Ok(()) => {
+                self.message = Some(format!("\"{}\" {}L, {}C written",
+                    path.display(), self.document.line_count(), self.document.len()));
+                if self.path.is_none() || self.path.as_ref() == Some(&path) {
+                    self.save_history(&path);
+                    self.path = Some(path);
+                    self.unsaved = false;
+                    self.saved_state = self.document.state();
+                }
+                true
+            },
+            Err(e) => {
+                self.message = Some(format!("Error writing \"{}\": {}", path.display(), e));
+                false
+            },
+        }
+    }
+
+    /// Keep the undo history for the next time `path` is opened, or
+    /// remove a stale undo file if there is no history to keep.
+    fn save_history(&mut self, path :&Path) {
+        if !self.options.undofile { return; }
+        let result = if self.document.can_undo() || self.document.can_redo() {
+            undofile::save(&real_path(path), &self.document)
+        } else {
+            undofile::remove(&real_path(path))
+        };
+        if let Err(e) = result {
+            self.message = Some(format!("Error writing undo file: {}", e));
+        }
+    }
+
+    /// Go to an older or newer state of the undo tree, for `g-`, `g+`,
+    /// `:earlier` and `:later`.
+    fn travel(&mut self, later :bool, travel :Travel) {
+        let pos = match (later, travel) {
+            (false, Travel::Steps(n)) => self.document.earlier(n),
+            (true, Travel::Steps(n)) => self.document.later(n),
+            (false, Travel::Time(span)) => self.document.earlier_by(span),
+            (true, Travel::Time(span)) => self.document.later_by(span),
+        };
+        match pos {
+            Some(pos) => {
+                self.cursor_pos = usize::min(pos, self.document.len());
+                self.unsaved = self.document.state() != self.saved_state;
+            },
+            None => self.message = Some(if later { "Already at newest change" } else { "Already at oldest change" }.to_string()),
+        }
+    }
+
+    /// The ends of the branches of the undo tree for `:undolist`, with the
+    /// number of changes to each and when it was made.
+    fn undo_list(&self) -> String {
+        let leaves = self.document.undo_leaves();
+        if leaves.is_empty() {
+            return "Nothing to undo".to_string();
+        }
+        let now = SystemTime::now();
+        leaves.iter().map(|leaf| {
+            let seconds = now.duration_since(leaf.time).map(|since| since.as_secs()).unwrap_or(0);
+            format!("#{} {} change{} {}", leaf.number, leaf.changes, if leaf.changes == 1 { "" } else { "s" }, ago(seconds))
+        }).collect::<Vec<_>>().join(", ")
+    }
+
+    fn execute_command(&mut self, line :&str) {
+        const UNSAVED :&str = "No write since last change (add ! to override)";
+        let command = match command::parse(line) {
+            Ok(Some(command)) => command,
+            Ok(None) => return,
+            Err(e) => {
+                self.message = Some(e);
+                return;
+            }
+        };
+        match command {
+            Command::Write(path) => { self.write(path); },
+            Command::WriteQuit => {
+                if self.write(None) { self.quit = true; }
+            },
+            Command::Quit { force } => {
+                if self.unsaved && !force {
+                    self.message = Some(UNSAVED.to_string());
+                } else {
+                    self.quit = true;
+                }
+            },
+            Command::Edit { path, force } => {
+                if self.unsaved && !force {
+                    self.message = Some(UNSAVED.to_string());
+                } else {
+                    match path.or_else(|| self.path.clone()) {
+                        Some(path) => self.load(path),
+                        None => self.message = Some("No file name".to_string()),
+                    }
+                }
+            },
+            Command::UndoList => self.message = Some(self.undo_list()),
+            Command::Earlier(travel) => self.travel(false, travel),
+            Command::Later(travel) => self.travel(true, travel),
+            Command::DeleteMarks(names) => {
+                for name in names {
+                    if let Some(id) = self.marks[name as usize - 'a' as usize].take() {
+                        self.document.remove_anchor(id);
+                    }
+                }
+            },
+            Command::Goto(address) => {
+                let line = self.address(address);
+                self.cursor_pos = self.document.line_start(line);
+            },
+            Command::Set(args) => {
+                if args.is_empty() {
+                    self.message = Some(self.options.describe());
+                }
+                for arg in &args {
+                    match self.options.set(arg) {
+                        Ok(Some(message)) => self.message = Some(message),
+                        Ok(None) => {},
+                        Err(e) => {
+                            self.message = Some(e);
+                            break;
+                        }
+                    }
+                }
+            },
+            Command::Substitute(substitute) => self.substitute(substitute),
+        }
+    }
+
+    /// The line index of a line address.
+    fn address(&self, address :Address) -> usize {
+        let last = self.document.line_count() - 1;
+        match address {
+            Address::Line(n) => usize::min(n.saturating_sub(1), last),
+            Address::Current => self.document.line_of(self.cursor_pos),
+            Address::Last => last,
+        }
+    }
+
+    fn substitute(&mut self, substitute :Substitute) {
+        let pattern = if substitute.pattern.is_empty() {
+            match self.last_search {
+                Some((ref last, _)) => last.clone(),
+                None => {
+                    self.message = Some("No previous regular expression".to_string());
+                    return;
+                },
+            }
+        } else {
+            substitute.pattern
+        };
+        let regex = match substitute.ignore_case {
+            Some(ignore_case) => regex::Regex::new(&pattern, ignore_case),
+            None => search::compile(&pattern, self.options.ignorecase, self.options.smartcase),
+        };
+        let regex = match regex {
+            Ok(regex) => regex,
+            Err(e) => {
+                self.message = Some(format!("Invalid pattern: {}", e));
+                return;
+            },
+        };
+        let (first, last) = match substitute.range {
+            Some((first, last)) => (self.address(first), self.address(last)),
+            None => {
+                let line = self.document.line_of(self.cursor_pos);
+                (line, line)
+            },
+        };
+        let (first, last) = (usize::min(first, last), usize::max(first, last));
+        self.last_search = Some((pattern.clone(), false));
+        let mut substitution = Substitution::new(&self.document, regex, substitute.replacement, substitute.global, first, last);
+        match substitution.current() {
+            Some(found) => self.cursor_pos = found.start(),
+            None => {
+                self.message = Some(format!("Pattern not found: {}", pattern));
+                return;
+            },
+        }
+        // All replacements are undone together.
+        self.document.begin_transaction();
+        if substitute.confirm {
+            self.substitution = Some(substitution);
+            self.mode = Mode::Confirm;
+        } else {
+            substitution.replace_all(&mut self.document);
+            self.substitution = Some(substitution);
+            self.finish_substitution();
+        }
+    }
+
+    /// Keys in Confirm mode: replace (`y`), skip (`n`), replace all the
+    /// rest (`a`), replace this one and stop (`l`), or stop (`q`).
+    fn confirm_input(&mut self, key :Key) {
+        let stop = {
+            let substitution = self.substitution.as_mut().unwrap();
+            match key {
+                Key::Char('y') => substitution.replace(&mut self.document),
+                Key::Char('n') => substitution.skip(&self.document),
+                Key::Char('a') => substitution.replace_all(&mut self.document),
+                Key::Char('l') => substitution.replace(&mut self.document),
+                _ => {},
+            }
+            match key {
+                Key::Char('l') | Key::Char('q') | Key::Escape => true,
+                _ => substitution.current().is_none(),
+            }
+        };
+        if stop {
+            self.finish_substitution();
+        } else if let Some(found) = self.substitution.as_ref().and_then(|s| s.current()) {
+            self.cursor_pos = found.start();
+        }
+    }
+
+    /// Close the undo group of a `:s` command and report what it did.
+    fn finish_substitution(&mut self) {
+        self.mode = Mode::Normal;
+        let substitution = match self.substitution.take() {
+            Some(substitution) => substitution,
+            None => return,
+        };
+        self.document.commit_transaction();
+        if let Some(line) = substitution.last_line {
+            self.cursor_pos = self.document.line_start(line);
+            self.unsaved = true;
+        }
+        if substitution.count > 0 {
+            let plural = |n, word| format!("{} {}{}", n, word, if n == 1 { "" } else { "s" });
+            self.message = Some(format!("{} on {}", plural(substitution.count, "substitution"), plural(substitution.lines, "line")));
+        }
+        self.clamp_cursor();
+    }
+
+    /// Number of text lines that fit in a window of height `h`, leaving room
+    /// for the status line.
+    fn text_lines(h :f32, metrics :Metrics) -> usize {
+        usize::max(1, ((h - metrics.line_height) / metrics.line_height).floor() as usize)
+    }
+
+    /// Adapt the viewport to a new window size.
+    pub fn resize(&mut self, (_, h): (f32, f32), metrics: Metrics) {
+        self.view_height = Editor::text_lines(h, metrics);
+        self.scroll_to_cursor();
+    }
+
+    /// Scroll as little as possible to keep the cursor `scrolloff` lines
+    /// away from the edges of the window.
+    fn scroll_to_cursor(&mut self) {
+        let scrolloff = usize::min(self.options.scrolloff, (self.view_height - 1) / 2);
+        let line = self.document.line_of(usize::min(self.cursor_pos, self.document.len()));
+        let last_line = self.document.line_count() - 1;
+        let top = line.saturating_sub(scrolloff);
+        let bottom = usize::min(line + scrolloff, last_line);
+        if top < self.view_line {
+            self.view_line = top;
+        } else if bottom >= self.view_line + self.view_height {
+            self.view_line = bottom + 1 - self.view_height;
+        }
+        self.view_line = usize::min(self.view_line, last_line);
+    }
+
+    /// Scroll the viewport by `delta` lines, moving the cursor along if it
+    /// would leave the window.
+    fn scroll(&mut self, delta :isize) {
+        let last_line = self.document.line_count() - 1;
+        self.view_line = usize::min(last_line, (self.view_line as isize + delta).max(0) as usize);
+        self.keep_cursor_in_view();
+    }
+
+    /// Move the cursor to the nearest line allowed by `scrolloff` within
+    /// the window.
+    fn keep_cursor_in_view(&mut self) {
+        let scrolloff = usize::min(self.options.scrolloff, (self.view_height - 1) / 2);
+        let line = self.document.line_of(usize::min(self.cursor_pos, self.document.len()));
+        let first_allowed = if self.view_line == 0 { 0 } else { self.view_line + scrolloff };
+        let last_allowed = (self.view_line + self.view_height - 1).saturating_sub(scrolloff);
+        if line < first_allowed {
+            self.move_lines(first_allowed as isize - line as isize);
+        } else if line > last_allowed && last_allowed >= first_allowed {
+            self.move_lines(last_allowed as isize - line as isize);
+        }
+    }
+
+    /// Move the cursor `delta` lines down, keeping its column if possible.
+    fn move_lines(&mut self, delta :isize) {
+        let line = self.document.line_of(usize::min(self.cursor_pos, self.document.len()));
+        let column = self.cursor_pos - self.document.line_start(line);
+        let last_line = self.document.line_count() - 1;
+        let target = usize::min(last_line, (line as isize + delta).max(0) as usize);
+        self.cursor_pos = self.document.line_start(target) + usize::min(column, self.document.line_len(target));
+        if self.mode != Mode::Insert {
+            self.clamp_cursor();
+        }
+    }
+
+    /// Scroll by half a window (`Ctrl-d`/`Ctrl-u`) or by a window minus two
+    /// lines (`Ctrl-f`/`Ctrl-b`).
+    fn scroll_page(&mut self, half :bool, down :bool) {
+        let amount = if half { self.view_height / 2 } else { self.view_height.saturating_sub(2) };
+        let amount = usize::max(1, amount) as isize * if down { 1 } else { -1 };
+        let line = self.document.line_of(self.cursor_pos) as isize;
+        let view_line = self.view_line;
+        self.scroll(amount);
+        if half {
+            // The cursor moves as far as the view did, or the full amount
+            // when the view cannot move.
+            let moved = self.view_line as isize - view_line as isize;
+            let target = line + if moved == 0 { amount } else { moved };
+            let current = self.document.line_of(self.cursor_pos) as isize;
+            self.move_lines(target - current);
+            self.keep_cursor_in_view();
+        }
+    }
+
+    /// The grapheme clusters of a line which start within `columns`, and
+    /// the character offset and display column where each starts. The last
+    /// entry is where the clusters end. Chunks are only taken until the
+    /// clusters fill `columns`.
+    fn layout<'a, I: Iterator<Item = &'a str>>(chunks :I, columns :usize) -> (String, Vec<(usize, usize)>) {
+        let mut line = String::new();
+        // Byte offset and column where the clusters known to be whole end.
+        let (mut done, mut filled) = (0, 0);
+        for chunk in chunks {
+            line.push_str(chunk);
+            let mut clusters = line[done..].graphemes(true).peekable();
+            // The last cluster may go on in the next chunk.
+            while let Some(grapheme) = clusters.next() {
+                if clusters.peek().is_none() { break; }
+                done += grapheme.len();
+                filled += document::width(grapheme);
+            }
+            if filled >= columns { break; }
+        }
+        let mut starts = Vec::new();
+        let (mut offset, mut column, mut end) = (0, 0, 0);
+        for (byte, grapheme) in line.grapheme_indices(true) {
+            if column >= columns { break; }
+            starts.push((offset, column));
+            offset += grapheme.chars().count();
+            column += document::width(grapheme);
+            end = byte + grapheme.len();
+        }
+        starts.push((offset, column));
+        (line[..end].to_string(), starts)
+    }
+
     pub fn render<F: FnMut(&TextCommand) -> Option<Rect>>(
         &self,
         (w, h): (f32, f32),
-        font_v: gfx_glyph::VMetrics,
-        font_h: gfx_glyph::HMetrics,
+        metrics: Metrics,
         mut text: F,
     ) {
         const FG: [f32; 4] = [1.0, 1.0, 1.0, 1.0];
         const BG: [f32; 4] = [0.1, 0.15, 0.2, 1.0];
         const CURSOR_INSERT: [f32; 4] = [1.0, 1.00, 0.2, 1.0];
         const CURSOR_NORMAL: [f32; 4] = [0.6, 0.6, 0.2, 1.0];
-        let text_height_px = font_v.ascent - font_v.descent;
+        const SELECTION: [f32; 4] = [0.25, 0.3, 0.45, 1.0];
+        const SEARCH: [f32; 4] = [0.45, 0.3, 0.1, 1.0];
+        let text_height_px = metrics.line_height;
 
         // assume monospace.
-        let glyph_w = font_h.advance_width;
+        let glyph_w = metrics.glyph_width;
 
         //let text_area   = ((0.0,0.0),(w,h - text_height_px));
-        let mut text_top = (1.5*glyph_w, 0.0);
+        let gutter_w = if self.options.number { 1.5*glyph_w } else { 0.0 };
+        let mut text_top = (gutter_w, 0.0);
         let text_bottom = (w, h - text_height_px);
 
         let status_area = ((0.0, h - text_height_px), (w, h));
@@ -54,13 +564,56 @@ impl Editor {
         //let string = &self.document.to_string();
 
         //println!("{:?}", self.document.to_string().lines().enumerate().collect::<Vec<_>>());
-        let alltext = {
-            let mut alltext = self.document.to_string();
-            alltext.push('\n');
-            alltext
+        let selection = match self.mode {
+            Mode::Visual | Mode::VisualLine => Some(self.selection().0),
+            _ => None,
         };
-        let mut num_chars :usize = 0;
-        for (line_no,line) in alltext.lines().enumerate() {
+        let highlight = self.highlight.as_ref().and_then(|highlight| highlight.1.as_ref());
+        let mut num_chars = self.document.line_start(self.view_line);
+        let visible = Editor::text_lines(h, metrics);
+        // Characters beyond the right edge of the window are not drawn.
+        let columns = ((w - gutter_w) / glyph_w).ceil().max(0.0) as usize;
+        let lines = self.document.lines(self.view_line..self.view_line + visible);
+        for (line_no,chunks) in (self.view_line..).zip(lines) {
+            let (line, starts) = Editor::layout(chunks, columns);
+            let line_len = self.document.line_len(line_no);
+            // Display column of a character offset in the line.
+            let column = |offset :usize| match starts.binary_search_by_key(&offset, |s| s.0) {
+                Ok(i) => starts[i].1,
+                Err(i) if i < starts.len() => starts[i - 1].1,
+                Err(_) => starts[starts.len() - 1].1 + offset - starts[starts.len() - 1].0,
+            };
+            if let Some(regex) = highlight {
+                for found in search::line_matches(&self.document, regex, line_no) {
+                    let (start, end) = (column(found.start() - num_chars), usize::min(column(found.end() - num_chars), columns));
+                    if start < end {
+                        text(&TextCommand {
+                            text: "",
+                            rect: ((text_top.0 + glyph_w*(start as f32), text_top.1), (text_top.0 + glyph_w*(end as f32), text_top.1 + text_height_px)),
+                            fg: FG,
+                            bg: Some(SEARCH),
+                        });
+                    }
+                }
+            }
+            if let Some(ref selection) = selection {
+                // The line break counts as one selectable column.
+                let start = usize::max(selection.start, num_chars);
+                let end = usize::min(selection.end, num_chars + line_len + 1);
+                if start < end {
+                    let right = match self.mode {
+                        Mode::VisualLine => w,
+                        _ => text_top.0 + glyph_w*(column(end - num_chars) as f32),
+                    };
+                    text(&TextCommand {
+                        text: "",
+                        rect: ((text_top.0 + glyph_w*(column(start - num_chars) as f32), text_top.1), (right, text_top.1 + text_height_px)),
+                        fg: FG,
+                        bg: Some(SELECTION),
+                    });
+                }
+            }
+
             let r = text(&TextCommand {
                 text: &line,
                 rect: (text_top, text_bottom),
@@ -68,17 +621,21 @@ impl Editor {
                 bg: None,
             });
 
-            text(&TextCommand {
-                text: &format!("{}", line_no),
-                rect: ((0.0, text_top.1), (0.0 + glyph_w*1.25, text_top.1 + text_height_px)),
-                fg: FG,
-                bg: Some(BG),
-            });
+            if self.options.number {
+                text(&TextCommand {
+                    text: &format!("{}", line_no),
+                    rect: ((0.0, text_top.1), (0.0 + glyph_w*1.25, text_top.1 + text_height_px)),
+                    fg: FG,
+                    bg: Some(BG),
+                });
+            }
 
             // Draw cursor here if it's on current line.
-            if num_chars <= self.cursor_pos && self.cursor_pos <= num_chars + line.len() {
-                let line_chars = self.cursor_pos - num_chars;
-                let cursor = ((text_top.0 + glyph_w*(line_chars as f32), text_top.1));
+            if num_chars <= self.cursor_pos && self.cursor_pos <= num_chars + line_len {
+                let offset = self.cursor_pos - num_chars;
+                let cursor = (text_top.0 + glyph_w*(column(offset) as f32), text_top.1);
+                let next = self.document.next_grapheme(self.cursor_pos) - num_chars;
+                let cursor_w = glyph_w * usize::max(1, column(next) - column(offset)) as f32;
 
                 match self.mode {
                     Mode::Insert =>  text(&TextCommand {
@@ -87,9 +644,9 @@ impl Editor {
                                         fg: FG, 
                                         bg: Some(CURSOR_INSERT),
                                     }),
-                    Mode::Normal =>  text(&TextCommand {
+                    Mode::Normal | Mode::Command | Mode::Visual | Mode::VisualLine | Mode::Search { .. } | Mode::Confirm =>  text(&TextCommand {
                                         text: "",
-                                        rect: (cursor, (cursor.0 + glyph_w, cursor.1 + text_height_px)),
+                                        rect: (cursor, (cursor.0 + cursor_w, cursor.1 + text_height_px)),
                                         fg: FG, 
                                         bg: Some(CURSOR_NORMAL),
                                     }),
@@ -104,96 +661,1141 @@ impl Editor {
                 text_top.1 += text_height_px;
             }
 
-            num_chars += line.len() +1;
+            num_chars += line_len + 1;
         }
 
         text(&TextCommand {
             //size: text_height_px,
-            text: &format!("{}{:?}", if self.unsaved { "* " } else { "  " }, self.mode),
+            text: &match (&self.mode, &self.message) {
+                (Mode::Command, _) => format!(":{}", self.command_line.text()),
+                (&Mode::Search { backward }, _) => format!("{}{}", if backward { '?' } else { '/' }, self.command_line.text()),
+                (Mode::Confirm, _) => format!("replace with {} (y/n/a/q/l)?",
+                                              self.substitution.as_ref().map(|s| s.replacement()).unwrap_or("")),
+                (_, Some(message)) => message.clone(),
+                (_, None) => format!("{}{:?}  {}  {}", if self.unsaved { "* " } else { "  " }, self.mode,
+                                     self.path.as_ref().map(|p| p.display().to_string()).unwrap_or_default(),
+                                     self.pending.keys()),
+            },
             rect: status_area,
             fg: FG,
             bg: Some(BG),
         });
+
+        if let Mode::Command | Mode::Search { .. } = self.mode {
+            let cursor = ((status_area.0).0 + glyph_w*((1 + self.command_line.cursor()) as f32), (status_area.0).1);
+            text(&TextCommand {
+                text: "",
+                rect: (cursor, (cursor.0 + 2.0, cursor.1 + text_height_px)),
+                fg: FG,
+                bg: Some(CURSOR_INSERT),
+            });
+        }
     }
 
-    pub fn input(&mut self, event: WindowEvent) {
-        use glutin::*;
-        match event {
-            WindowEvent::KeyboardInput {
-                input:
-                    KeyboardInput {
-                        virtual_keycode: Some(key),
-                        state: ElementState::Pressed,
-                        ..
-                    },
-                ..
-            } => match self.mode {
-                Mode::Insert => match key {
-                    VirtualKeyCode::Escape => {
-                        self.mode = Mode::Normal;
-                    }
-                    VirtualKeyCode::Return => {
-                        self.document.insert(self.cursor_pos, '\n');
-                        self.cursor_pos += 1;
-                        self.unsaved = true;
+    /// Keep the cursor on a character of its line, as Normal mode requires.
+    fn clamp_cursor(&mut self) {
+        let line = self.document.line_of(usize::min(self.cursor_pos, self.document.len()));
+        self.cursor_pos = usize::min(self.cursor_pos, motion::last_char(&self.document, line));
+    }
+
+    fn normal_command(&mut self, command :NormalCommand) {
+        match command.action {
+            Action::Move(motion) => {
+                if let Some(pos) = self.target(motion, command.count) {
+                    self.cursor_pos = pos;
+                }
+            },
+            Action::Operate(operator, motion) => self.operate(operator, command.register, motion, command.count),
+            Action::Insert(at) => self.start_insert(at),
+            Action::Undo => {
+                for _ in 0..command.count.unwrap_or(1) {
+                    match self.document.undo() {
+                        Some(pos) => self.cursor_pos = usize::min(pos, self.document.len()),
+                        None => break,
                     }
-                    VirtualKeyCode::Delete => {
-                        if self.cursor_pos < self.document.len() {
-                            self.document.remove(self.cursor_pos);
-                            self.unsaved = true;
-                        }
+                    self.unsaved = self.document.state() != self.saved_state;
+                }
+            },
+            Action::Travel { later } => self.travel(later, Travel::Steps(command.count.unwrap_or(1))),
+            Action::Put { before } => self.put(command.register, before, command.count.unwrap_or(1)),
+            Action::Scroll(to) => {
+                let line = self.document.line_of(self.cursor_pos);
+                self.view_line = match to {
+                    ScrollTo::Top => line,
+                    ScrollTo::Center => line.saturating_sub(self.view_height / 2),
+                    ScrollTo::Bottom => (line + 1).saturating_sub(self.view_height),
+                };
+            },
+            Action::CommandLine => {
+                self.command_line.clear();
+                self.mode = Mode::Command;
+            },
+            Action::SearchPrompt { backward } => {
+                self.command_line.clear();
+                self.search_origin = (self.cursor_pos, self.view_line);
+                self.mode = Mode::Search { backward };
+            },
+            Action::SearchNext { reverse } => self.search_next(reverse, command.count.unwrap_or(1)),
+            Action::SearchWord { backward } => self.search_word(backward, command.count.unwrap_or(1)),
+            Action::Visual { linewise } => {
+                self.visual_anchor = self.cursor_pos;
+                self.mode = if linewise { Mode::VisualLine } else { Mode::Visual };
+            },
+            Action::SetMark(name) => {
+                let mark = &mut self.marks[name as usize - 'a' as usize];
+                match *mark {
+                    Some(id) => self.document.set_anchor(id, self.cursor_pos),
+                    None => *mark = Some(self.document.add_anchor(self.cursor_pos, Gravity::Right)),
+                }
+            },
+        }
+        if let Mode::Normal = self.mode {
+            self.clamp_cursor();
+        }
+    }
+
+    fn start_insert(&mut self, at :InsertAt) {
+        let line = self.document.line_of(self.cursor_pos);
+        let line_start = self.document.line_start(line);
+        let line_end = line_start + self.document.line_len(line);
+        self.document.begin_transaction();
+        self.mode = Mode::Insert;
+        match at {
+            InsertAt::Cursor => {},
+            InsertAt::AfterCursor => self.cursor_pos = usize::min(self.document.next_grapheme(self.cursor_pos), line_end),
+            InsertAt::LineStart => self.cursor_pos = motion::first_non_blank(&self.document, line),
+            InsertAt::LineEnd => self.cursor_pos = line_end,
+            InsertAt::LineBelow => {
+                self.document.insert(line_end, '\n');
+                self.cursor_pos = line_end + 1;
+                self.unsaved = true;
+            },
+            InsertAt::LineAbove => {
+                self.document.insert(line_start, '\n');
+                self.cursor_pos = line_start;
+                self.unsaved = true;
+            },
+        }
+    }
+
+    /// Where `motion` leads from the cursor. Marks are looked up here, as
+    /// the document does not know their names.
+    fn target(&mut self, motion :Motion, count :Option<usize>) -> Option<usize> {
+        let name = match motion {
+            Motion::Mark(name) | Motion::MarkLine(name) => name,
+            _ => return motion.apply(&self.document, self.cursor_pos, count),
+        };
+        let mark = if name.is_ascii_lowercase() { self.marks[name as usize - 'a' as usize] } else { None };
+        match mark.map(|id| self.document.anchor(id)) {
+            Some(pos) if motion.is_linewise() => Some(motion::first_non_blank(&self.document, self.document.line_of(pos))),
+            Some(pos) => Some(pos),
+            None => {
+                self.message = Some("Mark not set".to_string());
+                None
+            },
+        }
+    }
+
+    /// Apply `operator` to the text between the cursor and the target of `motion`.
+    fn operate(&mut self, operator :Operator, register :Option<char>, motion :Motion, count :Option<usize>) {
+        let target = match self.target(motion, count) {
+            Some(target) => target,
+            None => return,
+        };
+        let doc = &self.document;
+
+        let (range, linewise) = if motion.is_linewise() {
+            let first = usize::min(doc.line_of(self.cursor_pos), doc.line_of(target));
+            let last  = usize::max(doc.line_of(self.cursor_pos), doc.line_of(target));
+            let start = doc.line_start(first);
+            let end = if last + 1 < doc.line_count() { doc.line_start(last + 1) } else { doc.len() };
+            (start..end, Some((first, last)))
+        } else {
+            let mut target = target;
+            if motion == Motion::WordForward {
+                let line = doc.line_of(self.cursor_pos);
+                let line_end = doc.line_start(line) + doc.line_len(line);
+                if operator == Operator::Change && self.cursor_pos < doc.len() && !doc.char_at(self.cursor_pos).is_whitespace() {
+                    target = doc.next_grapheme(motion::change_word_end(doc, self.cursor_pos, count));
+                } else if doc.line_of(target) > line {
+                    // The last word on a line is operated on up to the line break.
+                    target = line_end;
+                }
+            }
+            let start = usize::min(self.cursor_pos, target);
+            let end = usize::max(self.cursor_pos, target);
+            // An inclusive motion takes in the character at its end, but
+            // never the line break, e.g. for `D` on an empty line.
+            let end = if motion.is_inclusive() {
+                let line = doc.line_of(end);
+                usize::min(doc.next_grapheme(end), doc.line_start(line) + doc.line_len(line))
+            } else {
+                end
+            };
+            (start..end, None)
+        };
+
+        match operator {
+            Operator::Yank if linewise.is_some() => self.cursor_pos = usize::min(self.cursor_pos, target),
+            _ => self.cursor_pos = range.start,
+        }
+        self.apply_operator(operator, register, range.clone(), linewise);
+        if operator == Operator::ToggleCase {
+            self.cursor_pos = range.end;
+        }
+    }
+
+    /// Apply `operator` to `range`, which covers the lines `first..=last`
+    /// when `linewise` is `Some((first, last))`. Yanked and deleted text is
+    /// stored in `register`.
+    fn apply_operator(&mut self, operator :Operator, register :Option<char>, range :Range<usize>, linewise :Option<(usize, usize)>) {
+        let yanks = matches!(operator, Operator::Delete | Operator::Change | Operator::Yank);
+        if yanks && (linewise.is_some() || range.start < range.end) {
+            let mut text = self.document.slice(range.clone());
+            if linewise.is_some() && !text.ends_with('\n') {
+                text.push('\n');
+            }
+            self.registers.store(register, Register { text, linewise: linewise.is_some() }, operator != Operator::Yank);
+        }
+
+        match (operator, linewise) {
+            (Operator::Yank, _) => {},
+            (Operator::Delete, None) => {
+                self.document.remove_range(range.clone());
+                self.cursor_pos = range.start;
+                self.unsaved = true;
+            },
+            (Operator::Delete, Some((first, last))) => {
+                // Deleting the last line also removes the line break before it.
+                let start = if last + 1 == self.document.line_count() && first > 0 { range.start - 1 } else { range.start };
+                self.document.remove_range(start..range.end);
+                let line = usize::min(first, self.document.line_count() - 1);
+                self.cursor_pos = motion::first_non_blank(&self.document, line);
+                self.unsaved = true;
+            },
+            (Operator::Change, linewise) => {
+                // A linewise change keeps the line break of the last line.
+                let end = match linewise {
+                    Some((_, last)) => self.document.line_start(last) + self.document.line_len(last),
+                    None => range.end,
+                };
+                self.document.begin_transaction();
+                self.document.remove_range(range.start..end);
+                self.cursor_pos = range.start;
+                self.mode = Mode::Insert;
+                self.unsaved = true;
+            },
+            (Operator::ShiftRight, _) | (Operator::ShiftLeft, _) => {
+                let (first, last) = linewise.unwrap_or_else(|| {
+                    (self.document.line_of(range.start), self.document.line_of(range.end.saturating_sub(1)))
+                });
+                self.shift_lines(first, last, operator == Operator::ShiftRight);
+                self.cursor_pos = motion::first_non_blank(&self.document, first);
+            },
+            (Operator::ToggleCase, _) => {
+                let text = self.document.slice(range.clone());
+                let toggled = text.chars().flat_map(toggle_case).collect::<String>();
+                if toggled != text {
+                    self.document.begin_transaction();
+                    self.document.remove_range(range.clone());
+                    self.document.insert_str(range.start, &toggled);
+                    self.document.commit_transaction();
+                    self.unsaved = true;
+                }
+                self.cursor_pos = range.start;
+            },
+        }
+    }
+
+    /// Put `count` copies of the text in `register` after the cursor, or
+    /// before it if `before` is set. Linewise text goes below or above the
+    /// cursor line.
+    fn put(&mut self, register :Option<char>, before :bool, count :usize) {
+        let register = match self.registers.get(register) {
+            Some(register) => register,
+            None => {
+                self.message = Some(format!("Nothing in register {}", register.unwrap_or('"')));
+                return;
+            },
+        };
+        let text = register.text.repeat(count);
+        let line = self.document.line_of(self.cursor_pos);
+        if register.linewise {
+            let mut text = text;
+            if !text.ends_with('\n') {
+                text.push('\n');
+            }
+            let target = if before { line } else { line + 1 };
+            if target < self.document.line_count() {
+                self.document.insert_str(self.document.line_start(target), &text);
+            } else {
+                // Below the last line, which has no line break to insert after.
+                text.pop();
+                let end = self.document.len();
+                self.document.insert_str(end, &format!("\n{}", text));
+            }
+            self.cursor_pos = motion::first_non_blank(&self.document, target);
+        } else {
+            let at = if before || self.document.line_len(line) == 0 { self.cursor_pos } else { self.document.next_grapheme(self.cursor_pos) };
+            self.document.insert_str(at, &text);
+            self.cursor_pos = usize::max(at, self.document.prev_grapheme(at + text.chars().count()));
+        }
+        self.unsaved = true;
+    }
+
+    /// Indent or unindent the lines `first..=last` by `shiftwidth` columns.
+    fn shift_lines(&mut self, first :usize, last :usize, right :bool) {
+        let width = self.options.shiftwidth;
+        self.document.begin_transaction();
+        for line in first..=last {
+            let start = self.document.line_start(line);
+            let len = self.document.line_len(line);
+            if right {
+                if len > 0 {
+                    self.document.insert_str(start, &" ".repeat(width));
+                    self.unsaved = true;
+                }
+            } else {
+                let mut end = start;
+                let mut columns = 0;
+                while end < start + len && columns < width {
+                    match self.document.get(end) {
+                        ' ' => columns += 1,
+                        '\t' => columns = width,
+                        _ => break,
                     }
-                    VirtualKeyCode::Back => {
-                        if self.cursor_pos > 0 {
-                            self.document.remove(self.cursor_pos - 1);
-                            self.cursor_pos -= 1;
-                            self.unsaved = true;
-                        }
+                    end += 1;
+                }
+                if end > start {
+                    self.document.remove_range(start..end);
+                    self.unsaved = true;
+                }
+            }
+        }
+        self.document.commit_transaction();
+    }
+
+    /// The selected range in Visual mode, and the selected lines in
+    /// Visual-line mode.
+    fn selection(&self) -> (Range<usize>, Option<(usize, usize)>) {
+        let start = usize::min(self.visual_anchor, self.cursor_pos);
+        let end = usize::max(self.visual_anchor, self.cursor_pos);
+        match self.mode {
+            Mode::VisualLine => {
+                let first = self.document.line_of(start);
+                let last = self.document.line_of(end);
+                let end = if last + 1 < self.document.line_count() { self.document.line_start(last + 1) } else { self.document.len() };
+                (self.document.line_start(first)..end, Some((first, last)))
+            },
+            _ => (start..self.document.next_grapheme(end), None),
+        }
+    }
+
+    fn visual_input(&mut self, chr :char) {
+        if !self.pending.awaits_argument() {
+            let operator = match chr {
+                'd' | 'x' => Some(Operator::Delete),
+                'c' | 's' => Some(Operator::Change),
+                'y' => Some(Operator::Yank),
+                '>' => Some(Operator::ShiftRight),
+                '<' => Some(Operator::ShiftLeft),
+                '~' => Some(Operator::ToggleCase),
+                _ => None,
+            };
+            if let Some(operator) = operator {
+                let register = self.pending.register();
+                self.pending.reset();
+                let (range, linewise) = self.selection();
+                self.cursor_pos = usize::min(self.visual_anchor, self.cursor_pos);
+                self.mode = Mode::Normal;
+                self.apply_operator(operator, register, range, linewise);
+                if let Mode::Normal = self.mode {
+                    self.clamp_cursor();
+                }
+                return;
+            }
+            match chr {
+                'o' => {
+                    ::std::mem::swap(&mut self.visual_anchor, &mut self.cursor_pos);
+                    return;
+                },
+                'v' | 'V' => {
+                    self.pending.reset();
+                    self.mode = match (&self.mode, chr) {
+                        (Mode::Visual, 'v') | (Mode::VisualLine, 'V') => Mode::Normal,
+                        (_, 'v') => Mode::Visual,
+                        _ => Mode::VisualLine,
+                    };
+                    return;
+                },
+                _ => {},
+            }
+        }
+        if let Some(NormalCommand { count, action: Action::Move(motion), .. }) = self.pending.feed(chr) {
+            if let Some(pos) = self.target(motion, count) {
+                self.cursor_pos = pos;
+                self.clamp_cursor();
+            }
+        }
+    }
+
+    /// Keys on the command line, for both `:` commands and search patterns.
+    fn command_input(&mut self, key :Key) {
+        match key {
+            Key::Escape => self.cancel_command_line(),
+            Key::Enter => {
+                let line = self.command_line.text();
+                match ::std::mem::replace(&mut self.mode, Mode::Normal) {
+                    Mode::Search { backward } => {
+                        self.cursor_pos = self.search_origin.0;
+                        self.search(line, backward);
+                    },
+                    _ => self.execute_command(&line),
+                }
+            },
+            Key::Backspace => {
+                if self.command_line.is_empty() {
+                    self.cancel_command_line();
+                } else {
+                    self.command_line.backspace();
+                }
+            },
+            Key::Delete => self.command_line.delete(),
+            Key::Left => self.command_line.left(),
+            Key::Right => self.command_line.right(),
+            Key::Home => self.command_line.home(),
+            Key::End => self.command_line.end(),
+            Key::Char(chr) if !chr.is_control() => self.command_line.insert(chr),
+            _ => {},
+        }
+        if let Mode::Search { backward } = self.mode {
+            self.incremental_search(backward);
+        }
+    }
+
+    fn cancel_command_line(&mut self) {
+        if let Mode::Search { .. } = self.mode {
+            self.cursor_pos = self.search_origin.0;
+            self.view_line = self.search_origin.1;
+        }
+        self.mode = Mode::Normal;
+    }
+
+    /// Move the cursor to the first match of the pattern typed so far.
+    fn incremental_search(&mut self, backward :bool) {
+        self.cursor_pos = self.search_origin.0;
+        let pattern = self.command_line.text();
+        if pattern.is_empty() { return; }
+        if let Ok(regex) = search::compile(&pattern, self.options.ignorecase, self.options.smartcase) {
+            if let Some((found, _)) = search::find(&self.document, &regex, self.cursor_pos, backward) {
+                self.cursor_pos = found.start();
+            }
+        }
+    }
+
+    /// Search for `pattern`, or for the last pattern if it is empty, and
+    /// remember it for `n` and `N`.
+    fn search(&mut self, pattern :String, backward :bool) {
+        let pattern = if pattern.is_empty() {
+            match self.last_search {
+                Some((ref last, _)) => last.clone(),
+                None => {
+                    self.message = Some("No previous regular expression".to_string());
+                    return;
+                },
+            }
+        } else {
+            pattern
+        };
+        self.last_search = Some((pattern, backward));
+        self.search_next(false, 1);
+    }
+
+    /// Repeat the last search `count` times, in the opposite direction if
+    /// `reverse` is set.
+    fn search_next(&mut self, reverse :bool, count :usize) {
+        let (pattern, backward) = match self.last_search {
+            Some((ref pattern, backward)) => (pattern.clone(), backward != reverse),
+            None => {
+                self.message = Some("No previous regular expression".to_string());
+                return;
+            },
+        };
+        let regex = match search::compile(&pattern, self.options.ignorecase, self.options.smartcase) {
+            Ok(regex) => regex,
+            Err(e) => {
+                self.message = Some(format!("Invalid pattern: {}", e));
+                return;
+            },
+        };
+        let mut pos = self.cursor_pos;
+        for _ in 0..count {
+            match search::find(&self.document, &regex, pos, backward) {
+                Some((found, wrapped)) => {
+                    pos = found.start();
+                    if wrapped {
+                        self.message = Some(if backward { "search hit TOP, continuing at BOTTOM" }
+                                            else { "search hit BOTTOM, continuing at TOP" }.to_string());
                     }
-                    _ => {}
                 },
-                Mode::Normal => {}
+                None => {
+                    self.message = Some(format!("Pattern not found: {}", pattern));
+                    return;
+                },
+            }
+        }
+        self.cursor_pos = pos;
+    }
+
+    /// Search for the keyword under or after the cursor, as `*` and `#`.
+    fn search_word(&mut self, backward :bool, count :usize) {
+        let line = self.document.line_of(self.cursor_pos);
+        let line_end = self.document.line_start(line) + self.document.line_len(line);
+        let is_word = |c :char| c.is_alphanumeric() || c == '_';
+        let start = match (self.cursor_pos..line_end).find(|&i| is_word(self.document.get(i))) {
+            Some(start) => start,
+            None => {
+                self.message = Some("No string under cursor".to_string());
+                return;
+            },
+        };
+        let line_start = self.document.line_start(line);
+        let start = (line_start..start + 1).rev().take_while(|&i| is_word(self.document.get(i))).last().unwrap();
+        let end = (start..line_end).find(|&i| !is_word(self.document.get(i))).unwrap_or(line_end);
+        let word = self.document.slice(start..end);
+        self.cursor_pos = start;
+        self.last_search = Some((format!("\\<{}\\>", regex::Regex::escape(&word)), backward));
+        self.search_next(false, count);
+    }
+
+    pub fn input(&mut self, event :InputEvent) {
+        match event {
+            InputEvent::Key(key, modifiers) => self.key_press(key, modifiers),
+            InputEvent::Scroll(lines) => self.scroll(lines),
+        }
+        self.scroll_to_cursor();
+        self.update_highlight();
+    }
+
+    /// Compile the pattern to highlight, if it or the case settings have
+    /// changed since it was last compiled.
+    fn update_highlight(&mut self) {
+        let pattern = match self.mode {
+            Mode::Search { .. } => Some(self.command_line.text()),
+            _ => self.last_search.as_ref().map(|search| search.0.clone()),
+        };
+        let key = match pattern {
+            Some(pattern) => (pattern, self.options.ignorecase, self.options.smartcase),
+            None => {
+                self.highlight = None;
+                return;
             },
-            WindowEvent::ReceivedCharacter(chr) if !chr.is_control() => match self.mode {
-                Mode::Insert => {
+        };
+        if self.highlight.as_ref().map(|highlight| &highlight.0) != Some(&key) {
+            let regex = search::compile(&key.0, key.1, key.2).ok();
+            self.highlight = Some((key, regex));
+        }
+    }
+
+    /// `Ctrl-d`, `Ctrl-u`, `Ctrl-f` and `Ctrl-b`.
+    fn scroll_key(&mut self, chr :char) {
+        let (half, down) = match chr {
+            'd' => (true, true),
+            'u' => (true, false),
+            'f' => (false, true),
+            'b' => (false, false),
+            _ => return,
+        };
+        self.pending.reset();
+        self.scroll_page(half, down);
+    }
+
+    fn key_press(&mut self, key :Key, modifiers :Modifiers) {
+        if let Mode::Command | Mode::Search { .. } = self.mode {
+            if !modifiers.ctrl {
+                self.command_input(key);
+            }
+            return;
+        }
+        self.message = None;
+        match self.mode {
+            Mode::Insert => match key {
+                Key::Escape => {
+                    self.document.commit_transaction();
+                    self.mode = Mode::Normal;
+                    let line_start = self.document.line_start(self.document.line_of(self.cursor_pos));
+                    self.cursor_pos = usize::max(line_start, self.document.prev_grapheme(self.cursor_pos));
+                    self.clamp_cursor();
+                },
+                Key::Enter => {
+                    self.document.insert(self.cursor_pos, '\n');
+                    self.cursor_pos += 1;
+                    self.unsaved = true;
+                },
+                Key::Delete if self.cursor_pos < self.document.len() => {
+                    let end = self.document.next_grapheme(self.cursor_pos);
+                    for _ in self.cursor_pos..end {
+                        self.document.remove(self.cursor_pos);
+                    }
+                    self.unsaved = true;
+                },
+                Key::Backspace if self.cursor_pos > 0 => {
+                    let start = self.document.prev_grapheme(self.cursor_pos);
+                    for idx in (start..self.cursor_pos).rev() {
+                        self.document.remove(idx);
+                    }
+                    self.cursor_pos = start;
+                    self.unsaved = true;
+                },
+                Key::Char(chr) if !modifiers.ctrl && !chr.is_control() => {
                     self.document.insert(self.cursor_pos, chr);
                     self.cursor_pos += 1;
                     self.unsaved = true;
-                }
-                Mode::Normal => {
-                    match chr {
-                        'i' => {
-                            self.mode = Mode::Insert;
-                        }
-                        'h' => {
-                            self.cursor_pos = self.cursor_pos.saturating_sub(1);
-                        }
-                        'j' => {
-                            if let Some(next_line) = self.document.next_linebreak(self.cursor_pos) {
-                                let same_line = self.document.prev_linebreak(self.cursor_pos).map(|x|x+1).unwrap_or(0);
-                                let pos_in_line = self.cursor_pos - same_line;
-                                let eol = self.document.next_linebreak(next_line+1).unwrap_or(self.document.len());
-                                println!("goto next cursor{} next_line{} same_line{} pos_in_line{} eol{} ", 
-                                         self.cursor_pos,next_line, same_line,pos_in_line,eol);
-                                self.cursor_pos = usize::min(next_line+1+pos_in_line, eol);
-                                println!("Cursor  {}", self.cursor_pos);
-                            }
-                        }
-                        'k' => {
-                            if let Some(same_line) = self.document.prev_linebreak(self.cursor_pos) {
-                                let prev_line = self.document.prev_linebreak(same_line-1).unwrap_or(0);
-                                let pos_in_line = self.cursor_pos - same_line;
-                                self.cursor_pos = usize::min(prev_line + pos_in_line, same_line);
-                            }
-                        }
-                        'l' => {
-                            self.cursor_pos = self.cursor_pos.saturating_add(1);
-                        }
-                        _ => {}
-                    };
-                }
+                },
+                _ => {},
+            },
+            Mode::Visual | Mode::VisualLine => match key {
+                Key::Escape => {
+                    self.pending.reset();
+                    self.mode = Mode::Normal;
+                },
+                Key::Char(chr) if modifiers.ctrl => self.scroll_key(chr),
+                Key::Char(chr) if !modifiers.ctrl && !chr.is_control() => self.visual_input(chr),
+                _ => {},
+            },
+            Mode::Normal => match key {
+                Key::Escape => self.pending.reset(),
+                Key::Char('r') if modifiers.ctrl => {
+                    self.pending.reset();
+                    if let Some(pos) = self.document.redo() {
+                        self.cursor_pos = usize::min(pos, self.document.len());
+                        self.unsaved = self.document.state() != self.saved_state;
+                        self.clamp_cursor();
+                    }
+                },
+                Key::Char(chr) if modifiers.ctrl => self.scroll_key(chr),
+                Key::Char(chr) if !chr.is_control() => {
+                    if let Some(command) = self.pending.feed(chr) {
+                        self.normal_command(command);
+                    }
+                },
+                _ => {},
             },
-            _ => {}
+            Mode::Confirm => if !modifiers.ctrl { self.confirm_input(key) },
+            Mode::Command | Mode::Search { .. } => unreachable!(),
         }
     }
 }
+
+/// Write `document` to a temporary file next to `path` and rename it into
+/// place, so that the file is never left half-written. A symbolic link is
+/// written through rather than replaced.
+fn write_atomic(path :&Path, document :&Snapshot) -> io::Result<()> {
+    let path = real_path(path);
+    let file_name = path.file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?;
+    let tmp_path = path.with_file_name(format!(".{}.tmp", file_name.to_string_lossy()));
+    let file = fs::File::create(&tmp_path)?;
+    let result = write_file(file, document).and_then(|()| {
+        if let Ok(metadata) = fs::metadata(&path) {
+            fs::set_permissions(&tmp_path, metadata.permissions())?;
+        }
+        fs::rename(&tmp_path, &path)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// The file `path` refers to, with symbolic links resolved, or `path`
+/// itself if it does not exist yet.
+fn real_path(path :&Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Write all of `document` to `file` and wait until it is on disk.
+fn write_file(file :fs::File, document :&Snapshot) -> io::Result<()> {
+    let mut file = io::BufWriter::new(file);
+    for chunk in document.chunks(0..document.len()) {
+        file.write_all(chunk.as_bytes())?;
+    }
+    file.into_inner().map_err(|e| e.into_error())?.sync_all()
+}
+
+/// How long ago something happened, given in seconds.
+fn ago(seconds :u64) -> String {
+    if seconds < 100 {
+        format!("{} seconds ago", seconds)
+    } else if seconds < 100 * 60 {
+        format!("{} minutes ago", seconds / 60)
+    } else {
+        format!("{} hours ago", seconds / (60 * 60))
+    }
+}
+
+/// The other case of `c`, which may be more than one character.
+fn toggle_case(c :char) -> Vec<char> {
+    if c.is_lowercase() {
+        c.to_uppercase().collect()
+    } else if c.is_uppercase() {
+        c.to_lowercase().collect()
+    } else {
+        vec![c]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Editor, Mode};
+    use document::Document;
+    use input::{self, InputEvent};
+    use register::{MemoryClipboard, Registers};
+    use renderer::{Metrics, Recorder};
+    use std::env;
+    use std::fs;
+    use std::iter;
+    use std::path::Path;
+    use std::process;
+
+    const METRICS :Metrics = Metrics { line_height: 20.0, glyph_width: 10.0 };
+    /// Room for five lines of text and the status line.
+    const SIZE :(f32, f32) = (200.0, 120.0);
+
+    fn run(text :&str, keys :&str) -> Editor {
+        let mut editor = Editor::new();
+        editor.document = Document::new(text.to_string());
+        editor.registers = Registers::new(Box::new(MemoryClipboard::default()));
+        editor.resize(SIZE, METRICS);
+        for event in input::parse(keys) {
+            editor.input(event);
+        }
+        editor
+    }
+
+    fn check(text :&str, keys :&str, expect :&str, cursor :usize) {
+        let editor = run(text, keys);
+        assert_eq!((editor.document.to_string(), editor.cursor_pos), (expect.to_string(), cursor), "keys {:?}", keys);
+        assert_eq!(editor.mode, Mode::Normal, "keys {:?}", keys);
+    }
+
+    #[test]
+    fn modes() {
+        assert_eq!(run("", "ihello").mode, Mode::Insert);
+        assert_eq!(run("", "ihello<Esc>").mode, Mode::Normal);
+        assert_eq!(run("abc", "vl").mode, Mode::Visual);
+        assert_eq!(run("abc", "vV").mode, Mode::VisualLine);
+        assert_eq!(run("abc", ":se").mode, Mode::Command);
+        assert_eq!(run("abc", ":<BS>").mode, Mode::Normal);
+        check("", "ihello<Esc>0x", "ello", 0);
+        check("foo bar", "Aqq<Esc>", "foo barqq", 8);
+        check("foo\nbar", "oxx<Esc>", "foo\nxx\nbar", 5);
+        check("foo\nbar", "jOxx<Esc>", "foo\nxx\nbar", 5);
+        check("ab", "ix<BS><BS>y<Esc>", "yab", 0);
+        check("a\nb\nc", ":2<CR>x", "a\n\nc", 2);
+        check("a\nb\nc", ":$<CR>x", "a\nb\n", 4);
+        check("ab\ncd", "jl:.<CR>x", "ab\nd", 3);
+        check("e\u{301}x", "x", "x", 0);
+        check("a漢e\u{301}", "$x", "a漢", 1);
+        check("e\u{301}", "A<BS>x<Esc>", "x", 0);
+        check("漢x", "ay<Esc>", "漢yx", 1);
+    }
+
+    #[test]
+    fn operators() {
+        check("one two three", "dw", "two three", 0);
+        check("one two three", "wcwXX<Esc>", "one XX three", 5);
+        check("one two three", "2dw", "three", 0);
+        check("one two three", "d2w", "three", 0);
+        check("one two\nthree", "wdw", "one \nthree", 3);
+        check("a\nb\nc\nd", "jdd", "a\nc\nd", 2);
+        check("a\nb\nc\nd", "G2dk", "a", 0);
+        check("a\nb\nc\nd", "Gdk", "a\nb", 2);
+        check("a\nb\nc\nd", "3Gdd", "a\nb\nd", 4);
+        check("a\nb\nc\nd", "ddu", "a\nb\nc\nd", 0);
+        check("a\nb\nc\nd", "ddu<C-r>", "b\nc\nd", 0);
+        check("foo(bar)", "dt)", ")", 0);
+        check("foo(bar)", "df(", "bar)", 0);
+        check("foo bar", "$x", "foo ba", 5);
+        check("foo bar", "D", "", 0);
+        check("foo bar", "wD", "foo ", 3);
+        check("foo\nbar", "ccxx<Esc>", "xx\nbar", 1);
+        check("foo\nbar", "jccxx<Esc>u", "foo\nbar", 4);
+        check("foo\nbar", "yyjdd", "foo", 0);
+        check("abc", "lll", "abc", 2);
+        check("abc", "3x", "", 0);
+        check("abc", "l5x", "a", 0);
+        check("ab\ncd", "2>>", "    ab\n    cd", 4);
+        check("abc", "~~", "ABc", 2);
+        check("a\n\nb", "jD", "a\n\nb", 2);
+        check("a\n\nb", "jd$", "a\n\nb", 2);
+        check("a\n\nb", "jCx<Esc>", "a\nx\nb", 2);
+        check("a\n\nb", "yyjy$P", "a\na\n\nb", 2);
+    }
+
+    #[test]
+    fn grapheme_motions() {
+        // A combining mark, a ZWJ sequence and flags are each one cluster.
+        check("cafe\u{301} x", "w", "cafe\u{301} x", 6);
+        check("cafe\u{301} x", "dw", "x", 0);
+        check("cafe\u{301} x", "$b", "cafe\u{301} x", 0);
+        check("cafe\u{301} x", "e", "cafe\u{301} x", 3);
+        check("cafe\u{301} x", "de", " x", 0);
+        check("ae\u{301}x", "tx", "ae\u{301}x", 1);
+        check("ae\u{301}x", "dtx", "x", 0);
+        check("xe\u{301}y", "$Tx", "xe\u{301}y", 1);
+        check("a👨\u{200d}👩 b", "w", "a👨\u{200d}👩 b", 1);
+        check("a👨\u{200d}👩 b", "wdw", "ab", 1);
+        check("a👨\u{200d}👩 b", "$b", "a👨\u{200d}👩 b", 1);
+        check("🇳🇴🇸🇪 x", "w", "🇳🇴🇸🇪 x", 5);
+        check("🇳🇴🇸🇪 x", "e", "🇳🇴🇸🇪 x", 2);
+        check("🇳🇴🇸🇪 x", "$b", "🇳🇴🇸🇪 x", 0);
+        check("🇳🇴x🇸🇪", "$Tx", "🇳🇴x🇸🇪", 3);
+    }
+
+    #[test]
+    fn visual() {
+        check("one two three", "wvld", "one o three", 4);
+        check("one two three", "wvey", "one two three", 4);
+        check("one two three", "$vbd", "one two ", 7);
+        check("one two three", "wvlohd", "oneo three", 3);
+        check("a\nb\nc\nd", "jVjd", "a\nd", 2);
+        check("a\nb\nc\nd", "jVGd", "a", 0);
+        check("ab\ncd", "vj~", "AB\nCd", 0);
+        check("ab\ncd", "Vj>", "    ab\n    cd", 4);
+        check("      ab\ncd", "Vj<lt>", "  ab\ncd", 2);
+        check("abc", "vlcX<Esc>", "Xc", 0);
+        check("abc", "vV<Esc>x", "bc", 0);
+        check("abc", "vllvx", "ab", 1);
+        check("a\nb", "Vjdu", "a\nb", 0);
+    }
+
+    #[test]
+    fn marks() {
+        let text = "one\n  two\nthree";
+        check(text, "jwlmaggOx<Esc>`ax", "x\none\n  to\nthree", 9);
+        check(text, "jwlmagg'ax", "one\n  wo\nthree", 6);
+        check(text, "jwlmaGd'a", "one", 0);
+        check(text, "jwlmaG0d`a", "one\n  tthree", 7);
+        check(text, "jwlmajdd`ax", "one\n  to", 7);
+        check(text, "jwlmaddu`ax", "one\n  to\nthree", 7);
+        check(text, "jwlmaddp`ax", "one\nhree\n  two", 4);
+        assert_eq!(run(text, "`a").message, Some("Mark not set".to_string()));
+        assert_eq!(run(text, "jwlma0mbgg`b").cursor_pos, 4);
+        assert_eq!(run(text, "jmajmb:delm a<CR>`a").message, Some("Mark not set".to_string()));
+        assert_eq!(run(text, "jmajmb:delm a<CR>gg`b").cursor_pos, 10);
+        assert_eq!(run(text, "jmajmb:delm!<CR>`b").message, Some("Mark not set".to_string()));
+    }
+
+    #[test]
+    fn undo_tree() {
+        check("abc", "xulxg-", "bc", 0);
+        check("abc", "xulxg-g-", "abc", 0);
+        check("abc", "xulx2g-g+", "bc", 0);
+        check("abc", "xulxg-g+", "ac", 1);
+        check("abc", "xulxuu<C-r>", "ac", 1);
+        check("abc", "xulx:earlier<CR>", "bc", 0);
+        check("abc", "xulx:ea 1h<CR>", "abc", 1);
+        check("abc", "xulx:ea 1h<CR>:lat 1h<CR>", "ac", 1);
+        assert_eq!(run("abc", "g-").message, Some("Already at oldest change".to_string()));
+        assert!(!run("abc", "g-").unsaved);
+        assert!(!run("abc", "xulxg-g-").unsaved);
+        assert!(run("abc", "xulxg-").unsaved);
+        assert_eq!(run("abc", "xg+").message, Some("Already at newest change".to_string()));
+        assert_eq!(run("abc", ":undolist<CR>").message, Some("Nothing to undo".to_string()));
+        let list = run("abc", "xxulx:undol<CR>").message.unwrap();
+        assert!(list.starts_with("#2 2 changes ") && list.contains(", #3 2 changes "), "{}", list);
+    }
+
+    #[test]
+    fn write() {
+        let dir = env::temp_dir().join(format!("version6-editor-write-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("text.txt");
+
+        // A file which could not be read is not overwritten.
+        fs::write(&path, b"ok\n\xff").unwrap();
+        let mut editor = Editor::open(path.clone());
+        assert_eq!(editor.message, Some(format!("Error reading \"{}\": Invalid UTF-8 at byte 3", path.display())));
+        assert!(!editor.write(None));
+        assert_eq!(fs::read(&path).unwrap(), b"ok\n\xff");
+        assert!(editor.write(Some(dir.join("other.txt"))));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+
+        // Nor is one that is still being read, or failed to read part way.
+        let mut contents = "a\n".repeat(1 << 20).into_bytes();
+        contents.push(0xff);
+        fs::write(&path, &contents).unwrap();
+        let mut editor = Editor::open(path.clone());
+        assert!(editor.loading());
+        assert!(!editor.write(None));
+        assert_eq!(editor.message, Some(format!("\"{}\" is still being read; not writing it yet", path.display())));
+        while editor.loading() {
+            editor.poll();
+        }
+        assert_eq!(editor.message, Some(format!("Error reading \"{}\": Invalid UTF-8 at byte {}", path.display(), 2 << 20)));
+        assert!(!editor.write(None));
+        assert_eq!(fs::read(&path).unwrap(), contents);
+
+        // Undo and redo back to the written text leave nothing unsaved.
+        fs::write(&path, "abc\n").unwrap();
+        let mut editor = Editor::open(path.clone());
+        editor.registers = Registers::new(Box::new(MemoryClipboard::default()));
+        let mut unsaved = |keys| {
+            for event in input::parse(keys) {
+                editor.input(event);
+            }
+            editor.unsaved
+        };
+        assert_eq!((unsaved("x:w<CR>"), unsaved("x"), unsaved("u"), unsaved("u")), (false, true, false, true));
+        assert_eq!((unsaved("g+"), unsaved("g+"), unsaved("g-"), unsaved("<C-r>")), (false, true, false, true));
+
+        // Writing through a symbolic link leaves the link in place.
+        #[cfg(unix)]
+        {
+            let link = dir.join("link.txt");
+            fs::write(&path, "old\n").unwrap();
+            ::std::os::unix::fs::symlink(&path, &link).unwrap();
+            let mut editor = Editor::open(link.clone());
+            editor.document.insert_str(0, "new\n");
+            assert!(editor.write(None));
+            assert!(fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+            assert_eq!(fs::read_to_string(&path).unwrap(), "new\nold\n");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn undofile() {
+        let dir = env::temp_dir().join(format!("version6-editor-undofile-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("text.txt");
+        fs::write(&path, "one two\n").unwrap();
+        let open = |keys :&str| {
+            let mut editor = Editor::new();
+            editor.options.undofile = true;
+            editor.load(path.clone());
+            editor.registers = Registers::new(Box::new(MemoryClipboard::default()));
+            for event in input::parse(keys) {
+                editor.input(event);
+            }
+            editor
+        };
+
+        // Undo files are only kept when asked for.
+        let mut editor = Editor::open(path.clone());
+        editor.registers = Registers::new(Box::new(MemoryClipboard::default()));
+        for event in input::parse("x:w<CR>u:w<CR>") {
+            editor.input(event);
+        }
+        assert!(!dir.join(".text.txt.un~").exists());
+
+        open("dwAx<Esc>u:w<CR>");
+        assert_eq!(fs::read_to_string(&path).unwrap(), "two\n");
+        let editor = open("u");
+        assert_eq!(editor.document.to_string(), "one two\n");
+        assert_eq!(open("<C-r>").document.to_string(), "twox\n");
+
+        fs::write(&path, "changed\n").unwrap();
+        assert_eq!(open("u").document.to_string(), "changed\n");
+        assert_eq!(open("").message, Some("Undo history not restored: File changed since its undo history was saved".to_string()));
+        open("x:w<CR>");
+        assert_eq!(open("u").document.to_string(), "changed\n");
+
+        // A file written without history, or without the option, has no undo file.
+        fs::write(&path, "fresh\n").unwrap();
+        open(":w<CR>");
+        assert!(!dir.join(".text.txt.un~").exists());
+        open(":set noudf<CR>x:w<CR>");
+        assert!(!dir.join(".text.txt.un~").exists());
+
+        // The undo file of a symbolic link belongs to the file it points to.
+        #[cfg(unix)]
+        {
+            let link = dir.join("link.txt");
+            ::std::os::unix::fs::symlink(&path, &link).unwrap();
+            let mut editor = Editor::new();
+            editor.options.undofile = true;
+            editor.load(link.clone());
+            for event in input::parse("x:w<CR>") {
+                editor.input(event);
+            }
+            assert!(dir.join(".text.txt.un~").exists());
+            assert!(!dir.join(".link.txt.un~").exists());
+            assert_eq!(open("u").document.to_string(), "resh\n");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn registers() {
+        check("one two", "ywP", "one one two", 3);
+        check("one two", "dwp", "tone wo", 4);
+        check("one two", "x2p", "nooe two", 2);
+        check("a\nb\nc", "yyjp", "a\nb\na\nc", 4);
+        check("a\nb\nc", "yyGp", "a\nb\nc\na", 6);
+        check("a\nb\nc", "ddP", "a\nb\nc", 0);
+        check("a\nb\nc", "ddjP", "b\na\nc", 2);
+        check("a\nb\nc", "\"ayyjdd\"ap", "a\nc\na", 4);
+        check("a\nb\nc", "ddddp\"2p", "c\nb\na", 4);
+        check("a b", "\"+yw$\"+p", "a ba ", 4);
+        check("a b", "\"ayw\"Aywx\"ap", " a a b", 4);
+        check("abc", "vl\"qy$\"qp", "abcab", 4);
+        check("abc", "\"zp", "abc", 0);
+    }
+
+    /// Compare the rendering of `editor` with `golden/<name>.txt`, or
+    /// rewrite that file when `UPDATE_GOLDEN` is set.
+    fn golden(name :&str, editor :&Editor) {
+        let mut recorder = Recorder::new(METRICS);
+        editor.render(SIZE, METRICS, |cmd| recorder.record(cmd));
+        let rendered = recorder.to_text();
+
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/bin/version6/golden").join(format!("{}.txt", name));
+        if env::var_os("UPDATE_GOLDEN").is_some() {
+            fs::write(&path, &rendered).unwrap();
+        }
+        let expected = fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+        assert_eq!(rendered, expected, "rendering differs from {}", path.display());
+    }
+
+    #[test]
+    fn rendering() {
+        golden("lines", &run("first\nsecond\nthird", "jl"));
+        golden("nonumber", &run("first\nsecond", ":set nonumber<CR>j$"));
+        golden("insert", &run("abc", "A"));
+        golden("command_line", &run("abc", ":wq<Left>"));
+        golden("message", &run("abc", "\"zp"));
+        golden("visual", &run("one\ntwo\nthree", "lvj"));
+        golden("visual_line", &run("one\ntwo\nthree", "jVj"));
+        golden("scrolled", &run("0\n1\n2\n3\n4\n5\n6\n7\n8\n9", "6j"));
+        golden("search", &run("foo bar\nbar foo\nfoobar", "/o+b"));
+        golden("wide", &run("漢字 e\u{301}x", "ll"));
+
+        // Long lines are laid out only as far as the window reaches.
+        let chunks = ["ab", "c", "\u{301}d"].iter().cloned().chain(iter::repeat("x"));
+        assert_eq!(Editor::layout(chunks, 4), ("abc\u{301}d".to_string(), vec![(0, 0), (1, 1), (2, 2), (4, 3), (5, 4)]));
+        let chunks = ["漢字", "e"].iter().cloned();
+        assert_eq!(Editor::layout(chunks, 3), ("漢字".to_string(), vec![(0, 0), (1, 2), (2, 4)]));
+    }
+
+    #[test]
+    fn search() {
+        let text = "foo bar\nbar Foo\nfoobar";
+        let at = |keys| {
+            let editor = run(text, keys);
+            assert_eq!(editor.mode, Mode::Normal, "keys {:?}", keys);
+            editor.cursor_pos
+        };
+        assert_eq!(at("/foo<CR>"), 12);
+        assert_eq!(at("/foo<CR>n"), 16);
+        assert_eq!(at("/foo<CR>nn"), 0);
+        assert_eq!(at("/foo<CR>2n"), 0);
+        assert_eq!(at("/foo<CR>N"), 0);
+        assert_eq!(at("?bar<CR>"), 19);
+        assert_eq!(at("?bar<CR>n"), 8);
+        assert_eq!(at("?bar<CR>N"), 4);
+        assert_eq!(at("/Foo<CR>n"), 12);
+        assert_eq!(at(":set noic<CR>/foo<CR>"), 16);
+        assert_eq!(at("/o+b<CR>"), 17);
+        assert_eq!(at("/ba[rz]$<CR>"), 4);
+        assert_eq!(at("/foo<CR>gg/<CR>"), 12);
+        assert_eq!(at("/fo<Esc>"), 0);
+        assert_eq!(at("w*"), 8);
+        assert_eq!(at("w#"), 8);
+        assert_eq!(at("*"), 12);
+        assert_eq!(at("G#"), 16);
+        assert_eq!(at("2*"), 0);
+
+        // The cursor follows the pattern while it is typed.
+        let editor = run(text, "/fo");
+        assert_eq!((editor.mode, editor.cursor_pos), (Mode::Search { backward: false }, 12));
+        assert_eq!(run(text, "/xyz").cursor_pos, 0);
+
+        assert_eq!(run(text, "/xyz<CR>").message.unwrap(), "Pattern not found: xyz");
+        assert_eq!(run(text, "/foo<CR>nn").message.unwrap(), "search hit BOTTOM, continuing at TOP");
+        assert_eq!(run(text, "?foo<CR>").message.unwrap(), "search hit TOP, continuing at BOTTOM");
+        assert_eq!(run(text, "n").message.unwrap(), "No previous regular expression");
+        assert_eq!(run("  ", "*").message.unwrap(), "No string under cursor");
+        assert!(run(text, "/a(<CR>").message.unwrap().starts_with("Invalid pattern"));
+
+        // The highlighted pattern is compiled again when it or the case settings change.
+        let key = |keys| run(text, keys).highlight.map(|highlight| highlight.0);
+        assert_eq!(key("/fo"), Some(("fo".to_string(), true, true)));
+        assert_eq!(key("/foo<CR>:set noic<CR>"), Some(("foo".to_string(), false, true)));
+        assert!(run(text, "/a(<CR>").highlight.unwrap().1.is_none());
+        assert_eq!(key("jk"), None);
+    }
+
+    #[test]
+    fn substitute() {
+        let text = "foo bar\nbar foo foo\nfoo";
+        check(text, ":s/foo/x/<CR>", "x bar\nbar foo foo\nfoo", 0);
+        check(text, "j:s/foo/x/<CR>", "foo bar\nbar x foo\nfoo", 8);
+        check(text, "j:s/foo/x/g<CR>", "foo bar\nbar x x\nfoo", 8);
+        check(text, ":%s/foo/x/<CR>", "x bar\nbar x foo\nx", 16);
+        check(text, ":2,$s/o+/0/g<CR>", "foo bar\nbar f0 f0\nf0", 18);
+        check(text, ":%s/(b)(a)r/\\2\\1<lt>&>/<CR>", "foo ab<bar>\nab<bar> foo foo\nfoo", 12);
+        check(text, ":%s/ /\\n/g<CR>", "foo\nbar\nbar\nfoo\nfoo\nfoo", 12);
+        check(text, ":%s/foo/x/g<CR>u", text, 0);
+        check(text, "/bar<CR>:%s//baz/<CR>", "foo baz\nbaz foo foo\nfoo", 8);
+        check(text, ":%s/FOO/x/<CR>", text, 0);
+        check(text, ":%s/FOO/x/i<CR>", "x bar\nbar x foo\nx", 16);
+        check(text, ":%s/Foo/x/i<CR>", "x bar\nbar x foo\nx", 16);
+
+        // Confirmation
+        let editor = run(text, ":%s/foo/x/gc");
+        assert_eq!(editor.mode, Mode::Command);
+        let editor = run(text, ":%s/foo/x/gc<CR>");
+        assert_eq!((editor.mode, editor.cursor_pos), (Mode::Confirm, 0));
+        let editor = run(text, ":%s/foo/x/gc<CR>n");
+        assert_eq!((editor.mode, editor.cursor_pos), (Mode::Confirm, 12));
+        check(text, ":%s/foo/x/gc<CR>nyny", "foo bar\nbar x foo\nx", 18);
+        check(text, ":%s/foo/x/gc<CR>nyq", "foo bar\nbar x foo\nfoo", 8);
+        check(text, ":%s/foo/x/gc<CR>ya", "x bar\nbar x x\nx", 14);
+        check(text, ":%s/foo/x/gc<CR>nl", "foo bar\nbar x foo\nfoo", 8);
+        check(text, ":%s/foo/x/gc<CR><Esc>", text, 0);
+        check(text, ":%s/foo/x/gc<CR>yyyyu", text, 0);
+
+        assert_eq!(run(text, ":%s/foo/x/g<CR>").message.unwrap(), "4 substitutions on 3 lines");
+        assert_eq!(run(text, ":s/foo/x/<CR>").message.unwrap(), "1 substitution on 1 line");
+        assert_eq!(run(text, ":%s/foo/x/gc<CR>nnnn").message, None);
+        assert_eq!(run(text, ":s/baz/x/<CR>").message.unwrap(), "Pattern not found: baz");
+        assert_eq!(run(text, ":s//x/<CR>").message.unwrap(), "No previous regular expression");
+        assert!(run(text, ":s/(/x/<CR>").message.unwrap().starts_with("Invalid pattern"));
+        assert!(!run(text, ":s/baz/x/<CR>").unsaved);
+        assert!(run(text, ":s/foo/x/<CR>").unsaved);
+    }
+
+    fn view(text :&str, keys :&str) -> (usize, usize) {
+        let editor = run(text, keys);
+        (editor.view_line, editor.document.line_of(editor.cursor_pos))
+    }
+
+    #[test]
+    fn scrolling() {
+        let text = (0..30).map(|i| i.to_string()).collect::<Vec<_>>().join("\n");
+        // Five lines with a scroll-off of two.
+        assert_eq!(view(&text, "2j"), (0, 2));
+        assert_eq!(view(&text, "3j"), (1, 3));
+        assert_eq!(view(&text, "G"), (25, 29));
+        assert_eq!(view(&text, "Gk"), (25, 28));
+        assert_eq!(view(&text, "G3k"), (24, 26));
+        assert_eq!(view(&text, "<C-d>"), (2, 4));
+        assert_eq!(view(&text, "<C-d><C-d>"), (4, 6));
+        assert_eq!(view(&text, "<C-d><C-d><C-u>"), (2, 4));
+        assert_eq!(view(&text, "<C-f>"), (3, 5));
+        assert_eq!(view(&text, "<C-f><C-b>"), (0, 2));
+        // Without scroll-off, each puts the cursor line somewhere else.
+        assert_eq!(view(&text, ":set so=0<CR>10Gzt"), (9, 9));
+        assert_eq!(view(&text, ":set so=0<CR>10Gzz"), (7, 9));
+        assert_eq!(view(&text, ":set so=0<CR>10Gzb"), (5, 9));
+        assert_eq!(view(&text, "20Gzz"), (17, 19));
+
+        let mut editor = run(&text, "");
+        editor.input(InputEvent::Scroll(10));
+        assert_eq!((editor.view_line, editor.document.line_of(editor.cursor_pos)), (10, 12));
+        editor.input(InputEvent::Scroll(-3));
+        assert_eq!((editor.view_line, editor.document.line_of(editor.cursor_pos)), (7, 9));
+    }
+}
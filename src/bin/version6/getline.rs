@@ -0,0 +1,89 @@
+/// Single-line text editing, as in the `get_line` function of the earlier
+/// versions: a buffer and a cursor with simple movement and deletion.
+#[derive(Debug, Default)]
+pub struct GetLine {
+    text :Vec<char>,
+    cursor :usize,
+}
+
+impl GetLine {
+    pub fn new() -> Self {
+        GetLine { text: Vec::new(), cursor: 0 }
+    }
+
+    pub fn text(&self) -> String {
+        self.text.iter().collect()
+    }
+
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.text.clear();
+        self.cursor = 0;
+    }
+
+    pub fn insert(&mut self, c :char) {
+        self.text.insert(self.cursor, c);
+        self.cursor += 1;
+    }
+
+    /// Delete the character before the cursor.
+    pub fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.text.remove(self.cursor);
+        }
+    }
+
+    /// Delete the character under the cursor.
+    pub fn delete(&mut self) {
+        if self.cursor < self.text.len() {
+            self.text.remove(self.cursor);
+        }
+    }
+
+    pub fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    pub fn right(&mut self) {
+        self.cursor = usize::min(self.cursor + 1, self.text.len());
+    }
+
+    pub fn home(&mut self) {
+        self.cursor = 0;
+    }
+
+    pub fn end(&mut self) {
+        self.cursor = self.text.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GetLine;
+
+    #[test]
+    fn edit() {
+        let mut line = GetLine::new();
+        for c in "wq".chars() { line.insert(c); }
+        line.left();
+        line.backspace();
+        assert_eq!(line.text(), "q");
+        line.insert('x');
+        line.home();
+        line.delete();
+        line.end();
+        line.insert('!');
+        assert_eq!(line.text(), "q!");
+        assert_eq!(line.cursor(), 2);
+        line.right();
+        assert_eq!(line.cursor(), 2);
+    }
+}
@@ -0,0 +1,86 @@
+/// Keys the editor responds to, independent of the window system.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    Char(char),
+    Escape,
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Modifiers {
+    pub ctrl :bool,
+    pub alt :bool,
+    pub shift :bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputEvent {
+    /// A key press. Printable characters arrive as `Key::Char`, already
+    /// shifted; with `ctrl` held they are lowercase letters.
+    Key(Key, Modifiers),
+    /// Scroll the view by a number of lines, down if positive.
+    Scroll(isize),
+}
+
+impl InputEvent {
+    pub fn key(key :Key) -> Self {
+        InputEvent::Key(key, Modifiers::default())
+    }
+
+    pub fn ctrl(c :char) -> Self {
+        InputEvent::Key(Key::Char(c), Modifiers { ctrl: true, ..Modifiers::default() })
+    }
+}
+
+/// Parse keys in Vim notation, e.g. `ihello<Esc>0x` or `<C-r>`.
+#[cfg(test)]
+pub fn parse(keys :&str) -> Vec<InputEvent> {
+    let mut events = Vec::new();
+    let mut chars = keys.chars();
+    while let Some(c) = chars.next() {
+        if c != '<' {
+            events.push(InputEvent::key(Key::Char(c)));
+            continue;
+        }
+        let name = chars.by_ref().take_while(|c| *c != '>').collect::<String>();
+        events.push(match name.as_str() {
+            "Esc" => InputEvent::key(Key::Escape),
+            "CR" => InputEvent::key(Key::Enter),
+            "BS" => InputEvent::key(Key::Backspace),
+            "Del" => InputEvent::key(Key::Delete),
+            "Left" => InputEvent::key(Key::Left),
+            "Right" => InputEvent::key(Key::Right),
+            "Up" => InputEvent::key(Key::Up),
+            "Down" => InputEvent::key(Key::Down),
+            "Home" => InputEvent::key(Key::Home),
+            "End" => InputEvent::key(Key::End),
+            "lt" => InputEvent::key(Key::Char('<')),
+            name if name.starts_with("C-") && name.chars().count() == 3 => {
+                InputEvent::ctrl(name.chars().nth(2).unwrap())
+            },
+            _ => panic!("Unknown key <{}>", name),
+        });
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, InputEvent, Key};
+
+    #[test]
+    fn notation() {
+        assert_eq!(parse("a<Esc><lt><C-r>"), vec![InputEvent::key(Key::Char('a')),
+                                                 InputEvent::key(Key::Escape),
+                                                 InputEvent::key(Key::Char('<')),
+                                                 InputEvent::ctrl('r')]);
+    }
+}
@@ -5,19 +5,33 @@ extern crate gfx_window_glutin;
 extern crate glutin;
 extern crate font_loader;
 extern crate rand;
+extern crate unicode_segmentation;
+extern crate unicode_width;
 
 mod glyph_positioner;
 
 mod document;
-mod fenwick;
+mod treap;
 
+mod command;
 mod editor;
+mod getline;
+mod input;
+mod motion;
+mod normal;
+mod options;
+mod register;
+mod regex;
 mod renderer;
+mod search;
+mod substitute;
+mod undofile;
 
 use glutin::GlContext;
 use gfx::Device;
 use font_loader::system_fonts;
 use gfx_glyph::GlyphCruncher;
+use input::{InputEvent, Key, Modifiers};
 
 // structures for sending colored rects
 
@@ -91,21 +105,28 @@ fn exec(mut editor :editor::Editor) {
                                 scale = gfx_glyph::Scale::uniform(size);
                                 font_v = glyph_brush.fonts()[gfx_glyph::FontId::default()].v_metrics(scale);
                                 font_h = glyph_brush.fonts()[gfx_glyph::FontId::default()].glyph('a').scaled(scale).h_metrics();
+                            } else {
+                                editor.input(InputEvent::Scroll((-3.0 * y).round() as isize));
                             }
                         },
                         WindowEvent::Resized(size) => {
                             window.resize(size.to_physical(window.get_hidpi_factor()));
                             gfx_window_glutin::update_views(&window, &mut main_color, &mut main_depth);
                         },
-                        _ => { editor.input(event); },
+                        event => if let Some(event) = input_event(&event) {
+                            editor.input(event);
+                        },
                     }
                 }
             };
 
-            events_loop.run_forever(|event| {
-                handle(event);
-                glutin::ControlFlow::Break
-            });
+            // Don't wait for input while a file is loading in the background.
+            if !editor.loading() {
+                events_loop.run_forever(|event| {
+                    handle(event);
+                    glutin::ControlFlow::Break
+                });
+            }
 
             events_loop.poll_events(|event| {
                 handle(event);
@@ -113,7 +134,8 @@ fn exec(mut editor :editor::Editor) {
 
         }
 
-        if finished { break; }
+        editor.poll();
+        if finished || editor.quit_requested() { break; }
 
         encoder.clear(&main_color, [0.08, 0.02, 0.02, 1.0]);
         let (width, height, ..) = main_color.get_dimensions();
@@ -139,7 +161,12 @@ fn exec(mut editor :editor::Editor) {
                     color: c,
                     out: main_color.clone() });
             };
-            editor.render((width,height), font_v, font_h, |cmd: &renderer::TextCommand| {
+            let metrics = renderer::Metrics {
+                line_height: font_v.ascent - font_v.descent,
+                glyph_width: font_h.advance_width,
+            };
+            editor.resize((width,height), metrics);
+            editor.render((width,height), metrics, |cmd: &renderer::TextCommand| {
                 if let Some(c) = cmd.bg { rect_draw(&cmd.rect, c); }
                 let section = gfx_glyph::Section {
                     text: cmd.text,
@@ -164,6 +191,44 @@ fn exec(mut editor :editor::Editor) {
     }
 }
 
+/// Translate a window event to editor input. Printable characters come
+/// from `ReceivedCharacter`, other keys and Ctrl combinations from
+/// `KeyboardInput`.
+fn input_event(event :&glutin::WindowEvent) -> Option<InputEvent> {
+    use glutin::{ElementState, KeyboardInput, VirtualKeyCode, WindowEvent};
+    match *event {
+        WindowEvent::ReceivedCharacter(c) if !c.is_control() => Some(InputEvent::key(Key::Char(c))),
+        WindowEvent::KeyboardInput {
+            input: KeyboardInput { virtual_keycode: Some(code), state: ElementState::Pressed, modifiers, .. },
+            ..
+        } => {
+            let key = match code {
+                VirtualKeyCode::Escape => Key::Escape,
+                VirtualKeyCode::Return => Key::Enter,
+                VirtualKeyCode::Back => Key::Backspace,
+                VirtualKeyCode::Delete => Key::Delete,
+                VirtualKeyCode::Left => Key::Left,
+                VirtualKeyCode::Right => Key::Right,
+                VirtualKeyCode::Up => Key::Up,
+                VirtualKeyCode::Down => Key::Down,
+                VirtualKeyCode::Home => Key::Home,
+                VirtualKeyCode::End => Key::End,
+                // The letter keys are declared in order from A to Z.
+                _ if modifiers.ctrl && code as u32 >= VirtualKeyCode::A as u32 && code as u32 <= VirtualKeyCode::Z as u32 => {
+                    Key::Char((b'a' + (code as u32 - VirtualKeyCode::A as u32) as u8) as char)
+                },
+                _ => return None,
+            };
+            Some(InputEvent::Key(key, Modifiers { ctrl: modifiers.ctrl, alt: modifiers.alt, shift: modifiers.shift }))
+        },
+        _ => None,
+    }
+}
+
 fn main() {
-    exec(editor::Editor::new());
+    let editor = match std::env::args_os().nth(1) {
+        Some(path) => editor::Editor::open(path.into()),
+        None => editor::Editor::new(),
+    };
+    exec(editor);
 }
@@ -0,0 +1,263 @@
+use document::{self, Document};
+
+/// Cursor motions in Normal mode. Each motion is applied with an optional
+/// count, which for most motions repeats them and for `gg`/`G` selects a line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Motion {
+    Left,
+    Right,
+    Up,
+    Down,
+    WordForward,
+    WordBackward,
+    WordEnd,
+    LineStart,
+    LineEnd,
+    FirstLine,
+    LastLine,
+    FindForward(char),
+    TillForward(char),
+    FindBackward(char),
+    TillBackward(char),
+    /// The current line and `count - 1` lines below, as in `dd` and `yy`.
+    Line,
+    /// The position of a mark, with `` ` ``.
+    Mark(char),
+    /// The line of a mark, with `'`.
+    MarkLine(char),
+}
+
+impl Motion {
+    /// Operators act on whole lines for linewise motions.
+    pub fn is_linewise(&self) -> bool {
+        matches!(self, Motion::Up | Motion::Down | Motion::FirstLine | Motion::LastLine | Motion::Line | Motion::MarkLine(_))
+    }
+
+    /// Operators include the character at the target of inclusive motions.
+    pub fn is_inclusive(&self) -> bool {
+        matches!(self, Motion::WordEnd | Motion::LineEnd | Motion::FindForward(_) | Motion::TillForward(_))
+    }
+
+    /// The position reached by moving from `pos`, or `None` if the motion
+    /// fails (e.g. `j` on the last line or `f` without a match). Marks are
+    /// kept by the editor, which moves to them itself.
+    pub fn apply(&self, doc :&Document, pos :usize, count :Option<usize>) -> Option<usize> {
+        let n = count.unwrap_or(1);
+        let line = doc.line_of(pos);
+        let line_start = doc.line_start(line);
+        let line_end = line_start + doc.line_len(line);
+        let last_line = doc.line_count() - 1;
+
+        match *self {
+            Motion::Left => Some((0..n).fold(pos, |p, _| usize::max(line_start, doc.prev_grapheme(p)))),
+            Motion::Right => Some((0..n).fold(pos, |p, _| usize::min(line_end, doc.next_grapheme(p)))),
+            Motion::Up => {
+                if line == 0 { return None; }
+                Some(column_in_line(doc, column(doc, pos), line.saturating_sub(n)))
+            },
+            Motion::Down => {
+                if line == last_line { return None; }
+                Some(column_in_line(doc, column(doc, pos), usize::min(last_line, line + n)))
+            },
+            Motion::WordForward => Some((0..n).fold(pos, |p, _| word_forward(doc, p))),
+            Motion::WordBackward => Some((0..n).fold(pos, |p, _| word_backward(doc, p))),
+            Motion::WordEnd => Some((0..n).fold(pos, |p, _| word_end(doc, p))),
+            Motion::LineStart => Some(line_start),
+            Motion::LineEnd => {
+                let line = usize::min(last_line, line + n - 1);
+                Some(last_char(doc, line))
+            },
+            Motion::FirstLine => Some(first_non_blank(doc, usize::min(last_line, count.unwrap_or(1) - 1))),
+            Motion::LastLine => Some(first_non_blank(doc, usize::min(last_line, count.map(|c| c - 1).unwrap_or(last_line)))),
+            Motion::FindForward(c) => find_forward(doc, pos, line_end, c, n),
+            Motion::TillForward(c) => find_forward(doc, pos, line_end, c, n).map(|p| doc.prev_grapheme(p)),
+            Motion::FindBackward(c) => find_backward(doc, pos, line_start, c, n),
+            Motion::TillBackward(c) => find_backward(doc, pos, line_start, c, n).map(|p| doc.next_grapheme(p)),
+            Motion::Line => Some(column_in_line(doc, column(doc, pos), usize::min(last_line, line + n - 1))),
+            Motion::Mark(_) | Motion::MarkLine(_) => None,
+        }
+    }
+}
+
+/// Display column of `pos` in its line.
+pub fn column(doc :&Document, pos :usize) -> usize {
+    let line_start = doc.line_start(doc.line_of(pos));
+    doc.graphemes(line_start..pos).map(|(_, g)| document::width(&g)).sum()
+}
+
+/// Position of the grapheme cluster at display `column` on `line`, or the
+/// end of the line if it is shorter.
+fn column_in_line(doc :&Document, column :usize, line :usize) -> usize {
+    let start = doc.line_start(line);
+    let end = start + doc.line_len(line);
+    let mut width = 0;
+    for (pos, grapheme) in doc.graphemes(start..end) {
+        width += document::width(&grapheme);
+        if width > column { return pos; }
+    }
+    end
+}
+
+/// Position of the last grapheme cluster on `line`, or its start if it is
+/// empty.
+pub fn last_char(doc :&Document, line :usize) -> usize {
+    let start = doc.line_start(line);
+    let len = doc.line_len(line);
+    if len == 0 { start } else { usize::max(start, doc.prev_grapheme(start + len)) }
+}
+
+/// Position of the first non-blank character on `line`.
+pub fn first_non_blank(doc :&Document, line :usize) -> usize {
+    let start = doc.line_start(line);
+    let end = start + doc.line_len(line);
+    (start..end).find(|i| !doc.char_at(*i).is_whitespace()).unwrap_or(start)
+}
+
+#[derive(PartialEq, Eq)]
+enum Class {
+    Blank,
+    Word,
+    Punctuation,
+}
+
+fn class(c :char) -> Class {
+    if c.is_whitespace() {
+        Class::Blank
+    } else if c.is_alphanumeric() || c == '_' {
+        Class::Word
+    } else {
+        Class::Punctuation
+    }
+}
+
+/// Class of the grapheme cluster at `p`, by its first character.
+fn class_at(doc :&Document, p :usize) -> Class {
+    class(doc.char_at(p))
+}
+
+fn word_forward(doc :&Document, mut p :usize) -> usize {
+    let len = doc.len();
+    if p >= len { return len; }
+    let start = class_at(doc, p);
+    if start != Class::Blank {
+        while p < len && class_at(doc, p) == start { p = doc.next_grapheme(p); }
+    }
+    while p < len && class_at(doc, p) == Class::Blank { p = doc.next_grapheme(p); }
+    p
+}
+
+fn word_backward(doc :&Document, mut p :usize) -> usize {
+    if p == 0 { return 0; }
+    p = doc.prev_grapheme(p);
+    while p > 0 && class_at(doc, p) == Class::Blank { p = doc.prev_grapheme(p); }
+    let word = class_at(doc, p);
+    while p > 0 && word != Class::Blank && class_at(doc, doc.prev_grapheme(p)) == word { p = doc.prev_grapheme(p); }
+    p
+}
+
+fn word_end(doc :&Document, mut p :usize) -> usize {
+    let len = doc.len();
+    if doc.next_grapheme(p) >= len { return p; }
+    p = doc.next_grapheme(p);
+    while doc.next_grapheme(p) < len && class_at(doc, p) == Class::Blank { p = doc.next_grapheme(p); }
+    let word = class_at(doc, p);
+    while doc.next_grapheme(p) < len && word != Class::Blank && class_at(doc, doc.next_grapheme(p)) == word {
+        p = doc.next_grapheme(p);
+    }
+    p
+}
+
+/// Target of `cw`, which behaves like `ce` except that a word under the
+/// cursor is changed up to its end even if the cursor is on its last
+/// character.
+pub fn change_word_end(doc :&Document, pos :usize, count :Option<usize>) -> usize {
+    let next = doc.next_grapheme(pos);
+    let at_word_end = next >= doc.len() || class_at(doc, next) != class_at(doc, pos);
+    let first = if at_word_end { pos } else { word_end(doc, pos) };
+    (1..count.unwrap_or(1)).fold(first, |p, _| word_end(doc, p))
+}
+
+/// The `n`th grapheme cluster starting with `c` after `pos` and before `end`.
+fn find_forward(doc :&Document, pos :usize, end :usize, c :char, n :usize) -> Option<usize> {
+    let start = usize::min(end, doc.next_grapheme(pos));
+    doc.graphemes(start..end).filter(|(_, g)| g.starts_with(c)).map(|(i, _)| i).nth(n - 1)
+}
+
+/// The `n`th grapheme cluster starting with `c` before `pos` and not before
+/// `start`.
+fn find_backward(doc :&Document, pos :usize, start :usize, c :char, n :usize) -> Option<usize> {
+    let found = doc.graphemes(start..pos).filter(|(_, g)| g.starts_with(c)).map(|(i, _)| i).collect::<Vec<_>>();
+    found.into_iter().rev().nth(n - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Motion;
+    use document::Document;
+
+    #[test]
+    fn motions() {
+        let doc = Document::new("foo.bar  baz\n  qux(1)\n\nend".to_string());
+        let go = |m :Motion, pos, count| m.apply(&doc, pos, count);
+
+        assert_eq!(go(Motion::WordForward, 0, None), Some(3));
+        assert_eq!(go(Motion::WordForward, 0, Some(3)), Some(9));
+        assert_eq!(go(Motion::WordForward, 9, None), Some(15));
+        assert_eq!(go(Motion::WordBackward, 15, None), Some(9));
+        assert_eq!(go(Motion::WordBackward, 9, None), Some(4));
+        assert_eq!(go(Motion::WordBackward, 9, Some(2)), Some(3));
+        assert_eq!(go(Motion::WordEnd, 0, None), Some(2));
+        assert_eq!(go(Motion::WordEnd, 2, None), Some(3));
+        assert_eq!(go(Motion::WordEnd, 4, None), Some(6));
+
+        assert_eq!(go(Motion::LineStart, 10, None), Some(0));
+        assert_eq!(go(Motion::LineEnd, 1, None), Some(11));
+        assert_eq!(go(Motion::LineEnd, 1, Some(2)), Some(20));
+        assert_eq!(go(Motion::Right, 10, Some(5)), Some(12));
+        assert_eq!(go(Motion::Left, 14, Some(5)), Some(13));
+
+        assert_eq!(go(Motion::Down, 5, None), Some(18));
+        assert_eq!(go(Motion::Down, 5, Some(2)), Some(22));
+        assert_eq!(go(Motion::Up, 5, None), None);
+        assert_eq!(go(Motion::LastLine, 5, None), Some(23));
+        assert_eq!(go(Motion::FirstLine, 23, Some(2)), Some(15));
+        assert_eq!(go(Motion::FirstLine, 23, None), Some(0));
+
+        assert_eq!(go(Motion::FindForward('a'), 0, None), Some(5));
+        assert_eq!(go(Motion::FindForward('a'), 0, Some(2)), Some(10));
+        assert_eq!(go(Motion::FindForward('a'), 0, Some(3)), None);
+        assert_eq!(go(Motion::TillForward('a'), 0, None), Some(4));
+        assert_eq!(go(Motion::FindBackward('o'), 10, None), Some(2));
+        assert_eq!(go(Motion::TillBackward('o'), 10, None), Some(3));
+
+        // Clusters are stepped over whole, and columns count display width.
+        let doc = Document::new("e\u{301}漢x\nabcd\n🇳🇴".to_string());
+        let go = |m :Motion, pos, count| m.apply(&doc, pos, count);
+        assert_eq!(go(Motion::Right, 0, None), Some(2));
+        assert_eq!(go(Motion::Right, 0, Some(2)), Some(3));
+        assert_eq!(go(Motion::Left, 3, None), Some(2));
+        assert_eq!(go(Motion::Left, 2, None), Some(0));
+        assert_eq!(go(Motion::LineEnd, 0, None), Some(3));
+        assert_eq!(go(Motion::LineEnd, 10, None), Some(10));
+        assert_eq!(go(Motion::Down, 3, None), Some(8));
+        assert_eq!(go(Motion::Up, 7, None), Some(2));
+        assert_eq!(go(Motion::Up, 8, None), Some(3));
+        assert_eq!(go(Motion::Down, 6, None), Some(10));
+
+        // Word motions and finds step over clusters too.
+        let doc = Document::new("cafe\u{301} x👨\u{200d}👩 🇳🇴y".to_string());
+        let go = |m :Motion, pos, count| m.apply(&doc, pos, count);
+        assert_eq!(go(Motion::WordForward, 0, None), Some(6));
+        assert_eq!(go(Motion::WordForward, 6, None), Some(7));
+        assert_eq!(go(Motion::WordForward, 7, None), Some(11));
+        assert_eq!(go(Motion::WordBackward, 11, None), Some(7));
+        assert_eq!(go(Motion::WordBackward, 6, None), Some(0));
+        assert_eq!(go(Motion::WordEnd, 0, None), Some(3));
+        assert_eq!(go(Motion::WordEnd, 6, None), Some(7));
+        assert_eq!(go(Motion::FindForward('e'), 0, None), Some(3));
+        assert_eq!(go(Motion::TillForward('x'), 0, None), Some(5));
+        assert_eq!(go(Motion::TillForward('y'), 6, None), Some(11));
+        assert_eq!(go(Motion::TillBackward('e'), 6, None), Some(5));
+        assert_eq!(go(Motion::TillBackward('x'), 13, None), Some(7));
+    }
+}
@@ -0,0 +1,312 @@
+use motion::Motion;
+use register::Registers;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operator {
+    Delete,
+    Change,
+    Yank,
+    ShiftRight,
+    ShiftLeft,
+    ToggleCase,
+}
+
+/// Where `i`, `a`, `I`, `A`, `o` and `O` start Insert mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InsertAt {
+    Cursor,
+    AfterCursor,
+    LineStart,
+    LineEnd,
+    LineBelow,
+    LineAbove,
+}
+
+/// Where `zt`, `zz` and `zb` put the cursor line in the window.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScrollTo {
+    Top,
+    Center,
+    Bottom,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    Move(Motion),
+    Operate(Operator, Motion),
+    Insert(InsertAt),
+    Undo,
+    /// Go to an older or newer text state, across undo branches, with `g-`
+    /// and `g+`.
+    Travel { later :bool },
+    /// Put text from a register after or before the cursor, as `p` and `P`.
+    Put { before :bool },
+    Scroll(ScrollTo),
+    CommandLine,
+    /// Start a search with `/` or `?`.
+    SearchPrompt { backward :bool },
+    /// Repeat the last search with `n`, or in the other direction with `N`.
+    SearchNext { reverse :bool },
+    /// Search for the word under the cursor with `*` or `#`.
+    SearchWord { backward :bool },
+    Visual { linewise :bool },
+    /// Set a mark with `m`.
+    SetMark(char),
+}
+
+/// A complete Normal mode command, e.g. `3dw`, `gg` or `"ap`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NormalCommand {
+    pub count :Option<usize>,
+    /// The register selected with `"`.
+    pub register :Option<char>,
+    pub action :Action,
+}
+
+/// Accumulates Normal mode keys until they form a complete command:
+/// `["x] [count] action`, or `["x] [count] operator [count] motion`, where
+/// the two counts are multiplied.
+#[derive(Debug, Default)]
+pub struct Pending {
+    keys :String,
+    count :Option<usize>,
+    operator :Option<(Operator, Option<usize>)>,
+    register :Option<char>,
+    /// A key that needs another key to complete a command, like `g`, `f`
+    /// or `m`.
+    prefix :Option<char>,
+}
+
+impl Pending {
+    pub fn new() -> Self {
+        Pending::default()
+    }
+
+    /// The keys typed so far, for display in the status line.
+    pub fn keys(&self) -> &str {
+        &self.keys
+    }
+
+    pub fn reset(&mut self) {
+        *self = Pending::default();
+    }
+
+    /// Whether the next key completes a motion like `f` or `g`, or names a
+    /// register, and should not be interpreted as a command of its own.
+    pub fn awaits_argument(&self) -> bool {
+        self.prefix.is_some()
+    }
+
+    /// The register selected so far, for operators in Visual mode.
+    pub fn register(&self) -> Option<char> {
+        self.register
+    }
+
+    /// Add a key. Returns the command once it is complete; invalid key
+    /// sequences are discarded.
+    pub fn feed(&mut self, c :char) -> Option<NormalCommand> {
+        self.keys.push(c);
+        let action = self.action(c);
+        let incomplete = self.count.is_some() || self.operator.is_some() || self.prefix.is_some() || self.register.is_some();
+        if action.is_some() || !incomplete {
+            let count = match (self.operator.and_then(|(_, count)| count), self.count) {
+                (Some(a), Some(b)) => Some(a * b),
+                (a, b) => a.or(b),
+            };
+            let register = self.register;
+            self.reset();
+            return action.map(|action| NormalCommand { count, register, action });
+        }
+        None
+    }
+
+    fn action(&mut self, c :char) -> Option<Action> {
+        if let Some(prefix) = self.prefix.take() {
+            if prefix == '"' {
+                if !Registers::is_valid(c) || self.operator.is_some() {
+                    return self.invalid();
+                }
+                self.register = Some(c);
+                return None;
+            }
+            if prefix == 'm' {
+                if !c.is_ascii_lowercase() {
+                    return self.invalid();
+                }
+                return Some(Action::SetMark(c));
+            }
+            if prefix == 'z' {
+                return Some(Action::Scroll(match c {
+                    't' => ScrollTo::Top,
+                    'z' => ScrollTo::Center,
+                    'b' => ScrollTo::Bottom,
+                    _ => return self.invalid(),
+                }));
+            }
+            if prefix == 'g' && (c == '-' || c == '+') {
+                if self.operator.is_some() {
+                    return self.invalid();
+                }
+                return Some(Action::Travel { later: c == '+' });
+            }
+            let motion = match (prefix, c) {
+                ('g', 'g') => Motion::FirstLine,
+                ('f', c) => Motion::FindForward(c),
+                ('t', c) => Motion::TillForward(c),
+                ('F', c) => Motion::FindBackward(c),
+                ('T', c) => Motion::TillBackward(c),
+                ('`', c) => Motion::Mark(c),
+                ('\'', c) => Motion::MarkLine(c),
+                _ => return self.invalid(),
+            };
+            return Some(self.motion(motion));
+        }
+
+        if let Some(digit) = c.to_digit(10) {
+            if digit != 0 || self.count.is_some() {
+                self.count = Some(self.count.unwrap_or(0).saturating_mul(10).saturating_add(digit as usize));
+                return None;
+            }
+        }
+
+        let operator = match c {
+            'd' => Some(Operator::Delete),
+            'c' => Some(Operator::Change),
+            'y' => Some(Operator::Yank),
+            '>' => Some(Operator::ShiftRight),
+            '<' => Some(Operator::ShiftLeft),
+            _ => None,
+        };
+        if let Some(operator) = operator {
+            return match self.operator {
+                Some((pending, _)) if pending == operator => Some(self.motion(Motion::Line)),
+                Some(_) => self.invalid(),
+                None => {
+                    self.operator = Some((operator, self.count.take()));
+                    None
+                }
+            };
+        }
+
+        let motion = match c {
+            'h' => Motion::Left,
+            'j' => Motion::Down,
+            'k' => Motion::Up,
+            'l' | ' ' => Motion::Right,
+            'w' => Motion::WordForward,
+            'b' => Motion::WordBackward,
+            'e' => Motion::WordEnd,
+            '0' => Motion::LineStart,
+            '$' => Motion::LineEnd,
+            'G' => Motion::LastLine,
+            'g' | 'f' | 't' | 'F' | 'T' | '`' | '\'' => {
+                self.prefix = Some(c);
+                return None;
+            },
+            _ => {
+                if self.operator.is_some() {
+                    return self.invalid();
+                }
+                return self.command(c);
+            }
+        };
+        Some(self.motion(motion))
+    }
+
+    fn motion(&self, motion :Motion) -> Action {
+        match self.operator {
+            Some((operator, _)) => Action::Operate(operator, motion),
+            None => Action::Move(motion),
+        }
+    }
+
+    /// Commands which are not operators or motions.
+    fn command(&mut self, c :char) -> Option<Action> {
+        Some(match c {
+            'x' => Action::Operate(Operator::Delete, Motion::Right),
+            'X' => Action::Operate(Operator::Delete, Motion::Left),
+            's' => Action::Operate(Operator::Change, Motion::Right),
+            'D' => Action::Operate(Operator::Delete, Motion::LineEnd),
+            'C' => Action::Operate(Operator::Change, Motion::LineEnd),
+            'Y' => Action::Operate(Operator::Yank, Motion::Line),
+            '~' => Action::Operate(Operator::ToggleCase, Motion::Right),
+            'i' => Action::Insert(InsertAt::Cursor),
+            'a' => Action::Insert(InsertAt::AfterCursor),
+            'I' => Action::Insert(InsertAt::LineStart),
+            'A' => Action::Insert(InsertAt::LineEnd),
+            'o' => Action::Insert(InsertAt::LineBelow),
+            'O' => Action::Insert(InsertAt::LineAbove),
+            'u' => Action::Undo,
+            'p' => Action::Put { before: false },
+            'P' => Action::Put { before: true },
+            '"' | 'z' | 'm' => {
+                self.prefix = Some(c);
+                return None;
+            },
+            ':' => Action::CommandLine,
+            '/' => Action::SearchPrompt { backward: false },
+            '?' => Action::SearchPrompt { backward: true },
+            'n' => Action::SearchNext { reverse: false },
+            'N' => Action::SearchNext { reverse: true },
+            '*' => Action::SearchWord { backward: false },
+            '#' => Action::SearchWord { backward: true },
+            'v' => Action::Visual { linewise: false },
+            'V' => Action::Visual { linewise: true },
+            _ => return self.invalid(),
+        })
+    }
+
+    fn invalid(&mut self) -> Option<Action> {
+        self.count = None;
+        self.operator = None;
+        self.prefix = None;
+        self.register = None;
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, NormalCommand, Operator, Pending};
+    use motion::Motion;
+
+    fn parse(keys :&str) -> Vec<NormalCommand> {
+        let mut pending = Pending::new();
+        keys.chars().filter_map(|c| pending.feed(c)).collect()
+    }
+
+    fn cmd(count :Option<usize>, action :Action) -> NormalCommand {
+        NormalCommand { count, register: None, action }
+    }
+
+    #[test]
+    fn grammar() {
+        assert_eq!(parse("3j"), vec![cmd(Some(3), Action::Move(Motion::Down))]);
+        assert_eq!(parse("0"), vec![cmd(None, Action::Move(Motion::LineStart))]);
+        assert_eq!(parse("10l"), vec![cmd(Some(10), Action::Move(Motion::Right))]);
+        assert_eq!(parse("2d3w"), vec![cmd(Some(6), Action::Operate(Operator::Delete, Motion::WordForward))]);
+        assert_eq!(parse("dd"), vec![cmd(None, Action::Operate(Operator::Delete, Motion::Line))]);
+        assert_eq!(parse("3yy"), vec![cmd(Some(3), Action::Operate(Operator::Yank, Motion::Line))]);
+        assert_eq!(parse("ct)"), vec![cmd(None, Action::Operate(Operator::Change, Motion::TillForward(')')))]);
+        assert_eq!(parse("gg5G"), vec![cmd(None, Action::Move(Motion::FirstLine)),
+                                       cmd(Some(5), Action::Move(Motion::LastLine))]);
+        assert_eq!(parse("dyx"), vec![cmd(None, Action::Operate(Operator::Delete, Motion::Right))]);
+        assert_eq!(parse("gxi"), vec![cmd(None, Action::Insert(super::InsertAt::Cursor))]);
+        assert_eq!(parse("dQu"), vec![cmd(None, Action::Undo)]);
+        assert_eq!(parse("2>>"), vec![cmd(Some(2), Action::Operate(Operator::ShiftRight, Motion::Line))]);
+        assert_eq!(parse("<j"), vec![cmd(None, Action::Operate(Operator::ShiftLeft, Motion::Down))]);
+        assert_eq!(parse("\"a3yy"), vec![NormalCommand { count: Some(3), register: Some('a'),
+                                                         action: Action::Operate(Operator::Yank, Motion::Line) }]);
+        assert_eq!(parse("\"+P"), vec![NormalCommand { count: None, register: Some('+'), action: Action::Put { before: true } }]);
+        assert_eq!(parse("\"!p"), vec![cmd(None, Action::Put { before: false })]);
+        assert_eq!(parse("zzzx"), vec![cmd(None, Action::Scroll(super::ScrollTo::Center))]);
+        assert_eq!(parse("2N"), vec![cmd(Some(2), Action::SearchNext { reverse: true })]);
+        assert_eq!(parse("3~"), vec![cmd(Some(3), Action::Operate(Operator::ToggleCase, Motion::Right))]);
+        assert_eq!(parse("maxmBx"), vec![cmd(None, Action::SetMark('a')), cmd(None, Action::Operate(Operator::Delete, Motion::Right)),
+                                         cmd(None, Action::Operate(Operator::Delete, Motion::Right))]);
+        assert_eq!(parse("d'a`b"), vec![cmd(None, Action::Operate(Operator::Delete, Motion::MarkLine('a'))),
+                                        cmd(None, Action::Move(Motion::Mark('b')))]);
+        assert_eq!(parse("3g-g+dg-x"), vec![cmd(Some(3), Action::Travel { later: false }), cmd(None, Action::Travel { later: true }),
+                                            cmd(None, Action::Operate(Operator::Delete, Motion::Right))]);
+    }
+}
@@ -0,0 +1,114 @@
+/// Editor settings changed with `:set`.
+#[derive(Debug)]
+pub struct Options {
+    /// Show line numbers.
+    pub number :bool,
+    /// Search without regard to case.
+    pub ignorecase :bool,
+    /// Override `ignorecase` for search patterns with uppercase letters.
+    pub smartcase :bool,
+    /// Number of columns to indent with `>` and `<`.
+    pub shiftwidth :usize,
+    /// Minimum number of lines to keep above and below the cursor.
+    pub scrolloff :usize,
+    /// Keep the undo history of a file across sessions in an undo file.
+    /// Off by default, since the undo file holds all text ever typed; the
+    /// history is read when a file is opened with `:e` after setting it.
+    pub undofile :bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options { number: true, ignorecase: true, smartcase: true, shiftwidth: 4, scrolloff: 5, undofile: false }
+    }
+}
+
+impl Options {
+    /// Apply one `:set` argument: `name`, `noname`, `invname`, `name!`,
+    /// `name=value` or `name?`. Returns a message to show for queries.
+    pub fn set(&mut self, arg :&str) -> Result<Option<String>, String> {
+        let unknown = || format!("Unknown option: {}", arg);
+        if let Some(name) = arg.strip_suffix('?') {
+            if let Some(value) = self.number(name) {
+                return Ok(Some(format!("{}={}", name, value)));
+            }
+            let flag = self.flag(name).ok_or_else(unknown)?;
+            return Ok(Some(format!("{}{}", if *flag { "" } else { "no" }, name)));
+        }
+
+        if let Some(eq) = arg.find('=') {
+            let (name, value) = (&arg[..eq], &arg[eq + 1..]);
+            let value = value.parse().map_err(|_| format!("Invalid number: {}", arg))?;
+            *self.number(name).ok_or_else(unknown)? = value;
+        } else if let Some(name) = arg.strip_suffix('!').or_else(|| arg.strip_prefix("inv")) {
+            let flag = self.flag(name).ok_or_else(unknown)?;
+            *flag = !*flag;
+        } else if let Some(flag) = self.flag(arg) {
+            *flag = true;
+        } else if let Some(name) = arg.strip_prefix("no") {
+            *self.flag(name).ok_or_else(unknown)? = false;
+        } else if let Some(value) = self.number(arg) {
+            return Ok(Some(format!("{}={}", arg, value)));
+        } else {
+            return Err(unknown());
+        }
+        Ok(None)
+    }
+
+    fn flag(&mut self, name :&str) -> Option<&mut bool> {
+        match name {
+            "number" | "nu" => Some(&mut self.number),
+            "ignorecase" | "ic" => Some(&mut self.ignorecase),
+            "smartcase" | "scs" => Some(&mut self.smartcase),
+            "undofile" | "udf" => Some(&mut self.undofile),
+            _ => None,
+        }
+    }
+
+    fn number(&mut self, name :&str) -> Option<&mut usize> {
+        match name {
+            "shiftwidth" | "sw" => Some(&mut self.shiftwidth),
+            "scrolloff" | "so" => Some(&mut self.scrolloff),
+            _ => None,
+        }
+    }
+
+    /// Describe all options, for `:set` without arguments.
+    pub fn describe(&self) -> String {
+        let flag = |on :bool, name :&str| format!("{}{}", if on { "" } else { "no" }, name);
+        format!("{} {} {} shiftwidth={} scrolloff={} {}", flag(self.number, "number"), flag(self.ignorecase, "ignorecase"),
+                flag(self.smartcase, "smartcase"), self.shiftwidth, self.scrolloff, flag(self.undofile, "undofile"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Options;
+
+    #[test]
+    fn set() {
+        let mut options = Options::default();
+        assert_eq!(options.set("nonumber"), Ok(None));
+        assert!(!options.number);
+        assert_eq!(options.set("nu!"), Ok(None));
+        assert!(options.number);
+        assert_eq!(options.set("invnumber"), Ok(None));
+        assert_eq!(options.set("number?"), Ok(Some("nonumber".to_string())));
+        assert_eq!(options.set("nu"), Ok(None));
+        assert_eq!(options.set("number?"), Ok(Some("number".to_string())));
+        assert!(options.set("nosuchoption").is_err());
+
+        assert_eq!(options.set("sw=2"), Ok(None));
+        assert_eq!(options.shiftwidth, 2);
+        assert_eq!(options.set("shiftwidth"), Ok(Some("shiftwidth=2".to_string())));
+        assert!(options.set("sw=x").is_err());
+        assert!(options.set("number=1").is_err());
+
+        assert!(!options.undofile);
+        assert_eq!(options.set("udf"), Ok(None));
+        assert!(options.undofile);
+        assert!(options.describe().ends_with("scrolloff=5 undofile"));
+        assert_eq!(options.set("noudf"), Ok(None));
+        assert!(!options.undofile);
+    }
+}
@@ -0,0 +1,491 @@
+use std::iter::Peekable;
+use std::ops::Range;
+use std::str::Chars;
+
+/// A small regular expression engine which matches a stream of characters,
+/// so that text can be searched straight from the pieces of a `Document`.
+///
+/// Supported syntax: literals, `.`, `[...]` and `[^...]` classes with
+/// ranges, `\d \w \s \D \W \S`, `^`, `$`, `\b`, `\<`, `\>`, groups `(...)`,
+/// alternation `|` and the repetitions `*`, `+`, `?` and their lazy forms
+/// `*?`, `+?`, `??`. Matching uses a Pike VM: leftmost-first like Perl, in
+/// time linear in the length of the text.
+#[derive(Debug)]
+pub struct Regex {
+    prog :Vec<Inst>,
+    /// Number of groups, including the whole match as group 0.
+    groups :usize,
+    ignore_case :bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum ClassItem {
+    Range(char, char),
+    /// `d`, `w` or `s`, negated for the uppercase forms.
+    Named(char, bool),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Class {
+    negated :bool,
+    items :Vec<ClassItem>,
+}
+
+impl Class {
+    fn named(kind :char) -> Class {
+        Class { negated: false, items: vec![ClassItem::Named(kind.to_ascii_lowercase(), kind.is_uppercase())] }
+    }
+
+    fn matches(&self, c :char, ignore_case :bool) -> bool {
+        let test = |c :char| self.items.iter().any(|item| match *item {
+            ClassItem::Range(a, b) => a <= c && c <= b,
+            ClassItem::Named(kind, negated) => named_class(kind, c) != negated,
+        });
+        let found = test(c) || (ignore_case && (lower(c) != c && test(lower(c)) || upper(c) != c && test(upper(c))));
+        found != self.negated
+    }
+}
+
+fn named_class(kind :char, c :char) -> bool {
+    match kind {
+        'd' => c.is_ascii_digit(),
+        'w' => is_word(c),
+        _ => c.is_whitespace(),
+    }
+}
+
+fn is_word(c :char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn lower(c :char) -> char {
+    c.to_lowercase().next().unwrap_or(c)
+}
+
+fn upper(c :char) -> char {
+    c.to_uppercase().next().unwrap_or(c)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Assertion {
+    LineStart,
+    LineEnd,
+    WordBoundary,
+    WordStart,
+    WordEnd,
+}
+
+#[derive(Debug, PartialEq)]
+enum Node {
+    Empty,
+    Char(char),
+    Any,
+    Class(Class),
+    Assert(Assertion),
+    Group(Box<Node>, usize),
+    Concat(Vec<Node>),
+    Alternate(Vec<Node>),
+    Repeat { node :Box<Node>, min :usize, many :bool, greedy :bool },
+}
+
+#[derive(Debug)]
+enum Inst {
+    Char(char),
+    Any,
+    Class(Class),
+    Assert(Assertion),
+    /// Try the first branch before the second.
+    Split(usize, usize),
+    Jump(usize),
+    Save(usize),
+    Match,
+}
+
+struct Parser<'a> {
+    chars :Peekable<Chars<'a>>,
+    groups :usize,
+}
+
+impl<'a> Parser<'a> {
+    fn alternate(&mut self) -> Result<Node, String> {
+        let mut branches = vec![self.concat()?];
+        while self.chars.peek() == Some(&'|') {
+            self.chars.next();
+            branches.push(self.concat()?);
+        }
+        Ok(if branches.len() == 1 { branches.pop().unwrap() } else { Node::Alternate(branches) })
+    }
+
+    fn concat(&mut self) -> Result<Node, String> {
+        let mut nodes = Vec::new();
+        while let Some(&c) = self.chars.peek() {
+            if c == '|' || c == ')' { break; }
+            let atom = self.atom()?;
+            nodes.push(self.repeat(atom)?);
+        }
+        Ok(match nodes.len() {
+            0 => Node::Empty,
+            1 => nodes.pop().unwrap(),
+            _ => Node::Concat(nodes),
+        })
+    }
+
+    fn repeat(&mut self, mut node :Node) -> Result<Node, String> {
+        while let Some(&c) = self.chars.peek() {
+            let (min, many) = match c {
+                '*' => (0, true),
+                '+' => (1, true),
+                '?' => (0, false),
+                _ => break,
+            };
+            self.chars.next();
+            if let Node::Assert(_) | Node::Empty = node {
+                return Err(format!("Nothing to repeat before {}", c));
+            }
+            let greedy = self.chars.peek() != Some(&'?');
+            if !greedy { self.chars.next(); }
+            node = Node::Repeat { node: Box::new(node), min, many, greedy };
+        }
+        Ok(node)
+    }
+
+    fn atom(&mut self) -> Result<Node, String> {
+        Ok(match self.chars.next().unwrap() {
+            '(' => {
+                self.groups += 1;
+                let group = self.groups;
+                let node = self.alternate()?;
+                if self.chars.next() != Some(')') {
+                    return Err("Unmatched (".to_string());
+                }
+                Node::Group(Box::new(node), group)
+            },
+            '.' => Node::Any,
+            '^' => Node::Assert(Assertion::LineStart),
+            '$' => Node::Assert(Assertion::LineEnd),
+            '[' => Node::Class(self.class()?),
+            '*' | '+' | '?' => return Err("Nothing to repeat".to_string()),
+            '\\' => match self.chars.next() {
+                Some(c @ 'd') | Some(c @ 'w') | Some(c @ 's') |
+                Some(c @ 'D') | Some(c @ 'W') | Some(c @ 'S') => Node::Class(Class::named(c)),
+                Some('b') => Node::Assert(Assertion::WordBoundary),
+                Some('<') => Node::Assert(Assertion::WordStart),
+                Some('>') => Node::Assert(Assertion::WordEnd),
+                Some('t') => Node::Char('\t'),
+                Some(c) => Node::Char(c),
+                None => return Err("Trailing \\".to_string()),
+            },
+            c => Node::Char(c),
+        })
+    }
+
+    fn class(&mut self) -> Result<Class, String> {
+        let mut class = Class { negated: false, items: Vec::new() };
+        if self.chars.peek() == Some(&'^') {
+            self.chars.next();
+            class.negated = true;
+        }
+        let mut first = true;
+        loop {
+            let c = match self.chars.next() {
+                Some(']') if !first => return Ok(class),
+                Some('\\') => match self.chars.next() {
+                    Some(c @ 'd') | Some(c @ 'w') | Some(c @ 's') |
+                    Some(c @ 'D') | Some(c @ 'W') | Some(c @ 'S') => {
+                        class.items.push(ClassItem::Named(c.to_ascii_lowercase(), c.is_uppercase()));
+                        first = false;
+                        continue;
+                    },
+                    Some('t') => '\t',
+                    Some(c) => c,
+                    None => return Err("Unmatched [".to_string()),
+                },
+                Some(c) => c,
+                None => return Err("Unmatched [".to_string()),
+            };
+            first = false;
+            let mut ahead = self.chars.clone();
+            if ahead.next() == Some('-') && ahead.peek().is_some_and(|c| *c != ']') {
+                self.chars.next();
+                let end = self.chars.next().unwrap();
+                if end < c {
+                    return Err(format!("Invalid range {}-{}", c, end));
+                }
+                class.items.push(ClassItem::Range(c, end));
+            } else {
+                class.items.push(ClassItem::Range(c, c));
+            }
+        }
+    }
+}
+
+fn compile(node :&Node, prog :&mut Vec<Inst>) {
+    match *node {
+        Node::Empty => {},
+        Node::Char(c) => prog.push(Inst::Char(c)),
+        Node::Any => prog.push(Inst::Any),
+        Node::Class(ref class) => prog.push(Inst::Class(class.clone())),
+        Node::Assert(assertion) => prog.push(Inst::Assert(assertion)),
+        Node::Group(ref node, group) => {
+            prog.push(Inst::Save(2 * group));
+            compile(node, prog);
+            prog.push(Inst::Save(2 * group + 1));
+        },
+        Node::Concat(ref nodes) => {
+            for node in nodes {
+                compile(node, prog);
+            }
+        },
+        Node::Alternate(ref branches) => {
+            let mut jumps = Vec::new();
+            for (i, branch) in branches.iter().enumerate() {
+                if i + 1 < branches.len() {
+                    let split = prog.len();
+                    prog.push(Inst::Split(split + 1, 0));
+                    compile(branch, prog);
+                    jumps.push(prog.len());
+                    prog.push(Inst::Jump(0));
+                    let next = prog.len();
+                    prog[split] = Inst::Split(split + 1, next);
+                } else {
+                    compile(branch, prog);
+                }
+            }
+            let end = prog.len();
+            for jump in jumps {
+                prog[jump] = Inst::Jump(end);
+            }
+        },
+        Node::Repeat { ref node, min, many, greedy } => {
+            let split = |first :usize, second :usize| if greedy { Inst::Split(first, second) } else { Inst::Split(second, first) };
+            if min == 1 {
+                // e+ is e followed by a loop back to it.
+                let start = prog.len();
+                compile(node, prog);
+                let next = prog.len() + 1;
+                prog.push(split(start, next));
+            } else {
+                let start = prog.len();
+                prog.push(Inst::Jump(0));
+                compile(node, prog);
+                if many {
+                    prog.push(Inst::Jump(start));
+                }
+                let next = prog.len();
+                prog[start] = split(start + 1, next);
+            }
+        },
+    }
+}
+
+/// Positions of a match and its groups, as offsets from where the search
+/// started.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Captures {
+    slots :Vec<Option<usize>>,
+}
+
+impl Captures {
+    /// The range of group `i`, where group 0 is the whole match.
+    pub fn get(&self, i :usize) -> Option<Range<usize>> {
+        match (self.slots.get(2 * i), self.slots.get(2 * i + 1)) {
+            (Some(&Some(start)), Some(&Some(end))) => Some(start..end),
+            _ => None,
+        }
+    }
+
+    pub fn start(&self) -> usize {
+        self.slots[0].unwrap()
+    }
+
+    pub fn end(&self) -> usize {
+        self.slots[1].unwrap()
+    }
+
+    /// Shift all positions by `offset`.
+    pub fn offset(mut self, offset :usize) -> Captures {
+        for slot in self.slots.iter_mut() {
+            *slot = slot.map(|pos| pos + offset);
+        }
+        self
+    }
+}
+
+/// Threads of the VM for one position, in priority order.
+struct Threads {
+    list :Vec<(usize, Vec<Option<usize>>)>,
+    /// The step at which each instruction was last added, to add it once.
+    seen :Vec<usize>,
+}
+
+/// A position in the input, with the characters around it for assertions.
+#[derive(Clone, Copy)]
+struct At {
+    pos :usize,
+    prev :Option<char>,
+    next :Option<char>,
+}
+
+impl Regex {
+    pub fn new(pattern :&str, ignore_case :bool) -> Result<Regex, String> {
+        let mut parser = Parser { chars: pattern.chars().peekable(), groups: 0 };
+        let node = parser.alternate()?;
+        if parser.chars.next().is_some() {
+            return Err("Unmatched )".to_string());
+        }
+        let mut prog = vec![Inst::Save(0)];
+        compile(&node, &mut prog);
+        prog.push(Inst::Save(1));
+        prog.push(Inst::Match);
+        if ignore_case {
+            for inst in prog.iter_mut() {
+                if let Inst::Char(ref mut c) = *inst {
+                    *c = lower(*c);
+                }
+            }
+        }
+        Ok(Regex { prog, groups: parser.groups + 1, ignore_case })
+    }
+
+    /// A pattern which matches `text` literally.
+    pub fn escape(text :&str) -> String {
+        let mut escaped = String::new();
+        for c in text.chars() {
+            if "\\.[]()|*+?^$".contains(c) {
+                escaped.push('\\');
+            }
+            escaped.push(c);
+        }
+        escaped
+    }
+
+    fn add(&self, threads :&mut Threads, step :usize, pc :usize, mut slots :Vec<Option<usize>>, at :At) {
+        if threads.seen[pc] == step { return; }
+        threads.seen[pc] = step;
+        match self.prog[pc] {
+            Inst::Jump(to) => self.add(threads, step, to, slots, at),
+            Inst::Split(first, second) => {
+                self.add(threads, step, first, slots.clone(), at);
+                self.add(threads, step, second, slots, at);
+            },
+            Inst::Save(slot) => {
+                slots[slot] = Some(at.pos);
+                self.add(threads, step, pc + 1, slots, at);
+            },
+            Inst::Assert(assertion) => {
+                let word_before = at.prev.is_some_and(is_word);
+                let word_after = at.next.is_some_and(is_word);
+                let holds = match assertion {
+                    Assertion::LineStart => at.prev.is_none() && at.pos == 0,
+                    Assertion::LineEnd => at.next.is_none(),
+                    Assertion::WordBoundary => word_before != word_after,
+                    Assertion::WordStart => !word_before && word_after,
+                    Assertion::WordEnd => word_before && !word_after,
+                };
+                if holds {
+                    self.add(threads, step, pc + 1, slots, at);
+                }
+            },
+            _ => threads.list.push((pc, slots)),
+        }
+    }
+
+    /// Find the first match in `chars`, which should run to the end of a
+    /// line. `prev` is the character before the first one, or `None` at the
+    /// start of a line.
+    pub fn find<I: Iterator<Item = char>>(&self, chars :I, prev :Option<char>) -> Option<Captures> {
+        let mut current = Threads { list: Vec::new(), seen: vec![usize::MAX; self.prog.len()] };
+        let mut next_threads = Threads { list: Vec::new(), seen: vec![usize::MAX; self.prog.len()] };
+        let mut matched = None;
+        let mut chars = chars.peekable();
+        let mut prev = prev;
+        let mut pos = 0;
+        loop {
+            let c = chars.next();
+            if matched.is_none() {
+                let slots = vec![None; 2 * self.groups];
+                self.add(&mut current, pos, 0, slots, At { pos, prev, next: c });
+            }
+            if current.list.is_empty() && matched.is_some() {
+                break;
+            }
+            let folded = c.map(|c| if self.ignore_case { lower(c) } else { c });
+            let after = chars.peek().cloned();
+            for (pc, slots) in current.list.drain(..) {
+                let accepted = match (&self.prog[pc], folded) {
+                    (Inst::Match, _) => {
+                        matched = Some(Captures { slots });
+                        // Threads after this one have lower priority.
+                        break;
+                    },
+                    (Inst::Char(expected), Some(c)) => c == *expected,
+                    (Inst::Any, Some(_)) => true,
+                    (Inst::Class(class), Some(_)) => class.matches(c.unwrap(), self.ignore_case),
+                    _ => false,
+                };
+                if accepted {
+                    self.add(&mut next_threads, pos + 1, pc + 1, slots, At { pos: pos + 1, prev: c, next: after });
+                }
+            }
+            ::std::mem::swap(&mut current, &mut next_threads);
+            next_threads.list.clear();
+            if c.is_none() {
+                break;
+            }
+            prev = c;
+            pos += 1;
+        }
+        matched
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Regex;
+
+    fn find(pattern :&str, text :&str) -> Option<(usize, usize)> {
+        let regex = Regex::new(pattern, false).unwrap();
+        regex.find(text.chars(), None).map(|m| (m.start(), m.end()))
+    }
+
+    #[test]
+    fn matching() {
+        assert_eq!(find("b", "abc"), Some((1, 2)));
+        assert_eq!(find("x", "abc"), None);
+        assert_eq!(find("a.c", "xxabcxx"), Some((2, 5)));
+        assert_eq!(find("ab*", "abbbc"), Some((0, 4)));
+        assert_eq!(find("ab*?", "abbbc"), Some((0, 1)));
+        assert_eq!(find("ab+c", "ac abc"), Some((3, 6)));
+        assert_eq!(find("colou?r", "color"), Some((0, 5)));
+        assert_eq!(find("cat|dog", "hotdog"), Some((3, 6)));
+        assert_eq!(find("^a", "ba"), None);
+        assert_eq!(find("a$", "aba"), Some((2, 3)));
+        assert_eq!(find("[0-9]+", "abc 123"), Some((4, 7)));
+        assert_eq!(find("[^a-c]", "abcd"), Some((3, 4)));
+        assert_eq!(find("\\d\\s\\w", "a1 b"), Some((1, 4)));
+        assert_eq!(find("\\bfoo\\b", "foobar foo"), Some((7, 10)));
+        assert_eq!(find("\\<bar", "foobar bar"), Some((7, 10)));
+        assert_eq!(find("a\\.b", "axb a.b"), Some((4, 7)));
+        assert_eq!(find("(a|ab)(c|bcd)", "abcd"), Some((0, 4)));
+        assert_eq!(find("x*", "abc"), Some((0, 0)));
+        assert_eq!(find("(a*)*b", "aab"), Some((0, 3)));
+
+        let regex = Regex::new("(\\w+)@(\\w+)", false).unwrap();
+        let m = regex.find("mail me@host now".chars(), None).unwrap();
+        assert_eq!((m.get(0), m.get(1), m.get(2)), (Some(5..12), Some(5..7), Some(8..12)));
+
+        let regex = Regex::new("hello", true).unwrap();
+        assert_eq!(regex.find("Say HeLLo".chars(), None).map(|m| m.start()), Some(4));
+        let regex = Regex::new("[a-z]+", true).unwrap();
+        assert_eq!(regex.find("123ABC".chars(), None).map(|m| m.end()), Some(6));
+
+        // Not at the start of the line.
+        let regex = Regex::new("^b|\\bc", false).unwrap();
+        assert_eq!(regex.find("bc".chars(), Some('a')), None);
+
+        for pattern in &["(", "a)", "*a", "[a", "a\\", "[z-a]"] {
+            assert!(Regex::new(pattern, false).is_err(), "{}", pattern);
+        }
+        assert_eq!(Regex::escape("a.b*"), "a\\.b\\*");
+    }
+}
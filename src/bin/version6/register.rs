@@ -0,0 +1,189 @@
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Text stored by the yank and delete operators.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Register {
+    pub text :String,
+    /// Put whole lines above or below the cursor line, instead of inside it.
+    pub linewise :bool,
+}
+
+/// Access to the system clipboard, which backs the `+` register.
+pub trait Clipboard {
+    fn get(&mut self) -> Option<String>;
+    fn set(&mut self, text :&str);
+}
+
+/// A clipboard which is private to the editor.
+#[derive(Debug, Default)]
+pub struct MemoryClipboard {
+    text :Option<String>,
+}
+
+impl Clipboard for MemoryClipboard {
+    fn get(&mut self) -> Option<String> {
+        self.text.clone()
+    }
+
+    fn set(&mut self, text :&str) {
+        self.text = Some(text.to_string());
+    }
+}
+
+/// The system clipboard, through the `wl-copy`/`wl-paste`, `xclip` or
+/// `pbcopy`/`pbpaste` commands. Falls back to a private clipboard when
+/// none of them work.
+#[derive(Debug, Default)]
+pub struct SystemClipboard {
+    fallback :MemoryClipboard,
+}
+
+impl SystemClipboard {
+    fn commands() -> (&'static [&'static str], &'static [&'static str]) {
+        if cfg!(target_os = "macos") {
+            (&["pbcopy"], &["pbpaste"])
+        } else if ::std::env::var_os("WAYLAND_DISPLAY").is_some() {
+            (&["wl-copy"], &["wl-paste", "--no-newline"])
+        } else {
+            (&["xclip", "-selection", "clipboard"], &["xclip", "-selection", "clipboard", "-o"])
+        }
+    }
+}
+
+impl Clipboard for SystemClipboard {
+    fn get(&mut self) -> Option<String> {
+        let paste = SystemClipboard::commands().1;
+        let output = Command::new(paste[0]).args(&paste[1..]).stderr(Stdio::null()).output();
+        match output {
+            Ok(ref output) if output.status.success() => Some(String::from_utf8_lossy(&output.stdout).into_owned()),
+            _ => self.fallback.get(),
+        }
+    }
+
+    fn set(&mut self, text :&str) {
+        self.fallback.set(text);
+        let copy = SystemClipboard::commands().0;
+        let child = Command::new(copy[0]).args(&copy[1..])
+            .stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null())
+            .spawn();
+        if let Ok(mut child) = child {
+            if let Some(mut stdin) = child.stdin.take() {
+                let _ = stdin.write_all(text.as_bytes());
+            }
+            let _ = child.wait();
+        }
+    }
+}
+
+/// The registers used by yank, delete and put: the unnamed register `"`,
+/// the yank register `0`, the delete history `1`–`9`, the named registers
+/// `a`–`z` and the clipboard register `+`.
+pub struct Registers {
+    unnamed :Register,
+    /// Registers `0` to `9`.
+    numbered :Vec<Register>,
+    named :Vec<Register>,
+    clipboard :Box<dyn Clipboard>,
+}
+
+impl Registers {
+    pub fn new(clipboard :Box<dyn Clipboard>) -> Self {
+        Registers {
+            unnamed: Register::default(),
+            numbered: vec![Register::default(); 10],
+            named: vec![Register::default(); 26],
+            clipboard,
+        }
+    }
+
+    /// Whether `name` can be used after `"`.
+    pub fn is_valid(name :char) -> bool {
+        name.is_ascii_alphanumeric() || name == '"' || name == '+'
+    }
+
+    /// Store yanked (`delete == false`) or deleted text in the register
+    /// `name`, or in the unnamed register if it is `None`. An uppercase
+    /// name appends to the named register.
+    pub fn store(&mut self, name :Option<char>, register :Register, delete :bool) {
+        let register = match name {
+            Some(c) if c.is_ascii_lowercase() => {
+                self.named[c as usize - 'a' as usize] = register.clone();
+                register
+            },
+            Some(c) if c.is_ascii_uppercase() => {
+                let named = &mut self.named[c.to_ascii_lowercase() as usize - 'a' as usize];
+                if register.linewise && !named.linewise && !named.text.is_empty() {
+                    named.text.push('\n');
+                }
+                named.text.push_str(&register.text);
+                named.linewise |= register.linewise;
+                named.clone()
+            },
+            Some('+') => {
+                self.clipboard.set(&register.text);
+                register
+            },
+            _ if delete => {
+                self.numbered.insert(1, register.clone());
+                self.numbered.truncate(10);
+                register
+            },
+            _ => {
+                self.numbered[0] = register.clone();
+                register
+            },
+        };
+        self.unnamed = register;
+    }
+
+    /// The contents of the register `name`, or of the unnamed register.
+    pub fn get(&mut self, name :Option<char>) -> Option<Register> {
+        let register = match name {
+            None | Some('"') => self.unnamed.clone(),
+            Some('+') => {
+                let text = self.clipboard.get()?;
+                let linewise = text.ends_with('\n');
+                Register { text, linewise }
+            },
+            Some(c) if c.is_ascii_digit() => self.numbered[c as usize - '0' as usize].clone(),
+            Some(c) if c.is_ascii_alphabetic() => self.named[c.to_ascii_lowercase() as usize - 'a' as usize].clone(),
+            Some(_) => return None,
+        };
+        if register.text.is_empty() && !register.linewise { None } else { Some(register) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemoryClipboard, Register, Registers};
+
+    fn reg(text :&str, linewise :bool) -> Register {
+        Register { text: text.to_string(), linewise }
+    }
+
+    #[test]
+    fn registers() {
+        let mut registers = Registers::new(Box::new(MemoryClipboard::default()));
+        assert_eq!(registers.get(None), None);
+
+        registers.store(None, reg("yanked", false), false);
+        registers.store(None, reg("one\n", true), true);
+        registers.store(None, reg("two", false), true);
+        assert_eq!(registers.get(None), Some(reg("two", false)));
+        assert_eq!(registers.get(Some('0')), Some(reg("yanked", false)));
+        assert_eq!(registers.get(Some('1')), Some(reg("two", false)));
+        assert_eq!(registers.get(Some('2')), Some(reg("one\n", true)));
+        assert_eq!(registers.get(Some('3')), None);
+
+        registers.store(Some('a'), reg("foo", false), false);
+        registers.store(Some('A'), reg("bar", false), true);
+        assert_eq!(registers.get(Some('a')), Some(reg("foobar", false)));
+        assert_eq!(registers.get(None), Some(reg("foobar", false)));
+        assert_eq!(registers.get(Some('1')), Some(reg("two", false)));
+
+        registers.store(Some('+'), reg("line\n", true), false);
+        assert_eq!(registers.get(Some('+')), Some(reg("line\n", true)));
+        assert_eq!(registers.get(Some('b')), None);
+    }
+}
@@ -9,3 +9,54 @@ pub struct TextCommand<'a> {
     pub bg :Option<Color>,
 }
 
+
+/// Font measurements needed for layout. The editor assumes a monospace
+/// font.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Metrics {
+    pub line_height :f32,
+    pub glyph_width :f32,
+}
+
+/// A backend which records the commands instead of drawing them, laying
+/// out text in monospace cells of the given metrics.
+#[cfg(test)]
+#[derive(Debug)]
+pub struct Recorder {
+    metrics :Metrics,
+    commands :Vec<(String, Rect, Color, Option<Color>)>,
+}
+
+#[cfg(test)]
+impl Recorder {
+    pub fn new(metrics :Metrics) -> Self {
+        Recorder { metrics, commands: Vec::new() }
+    }
+
+    /// Record a command and return the bounds of its text, like the glyph
+    /// renderer does: `None` for text without visible characters.
+    pub fn record(&mut self, cmd :&TextCommand) -> Option<Rect> {
+        self.commands.push((cmd.text.to_string(), cmd.rect, cmd.fg, cmd.bg));
+        if cmd.text.trim().is_empty() {
+            return None;
+        }
+        let (x, y) = cmd.rect.0;
+        let width = self.metrics.glyph_width * cmd.text.chars().count() as f32;
+        Some(((x, y), (x + width, y + self.metrics.line_height)))
+    }
+
+    /// One line per command: rect, foreground, background and text.
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        for &(ref text, ((x0, y0), (x1, y1)), fg, bg) in &self.commands {
+            out.push_str(&format!("({:.1},{:.1})-({:.1},{:.1}) fg={} bg={} {:?}\n",
+                                  x0, y0, x1, y1, hex(fg), bg.map(hex).unwrap_or_else(|| "-".to_string()), text));
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+fn hex(color :Color) -> String {
+    color.iter().map(|c| format!("{:02x}", (c.clamp(0.0, 1.0) * 255.0).round() as u8)).collect()
+}
@@ -0,0 +1,97 @@
+use document::Document;
+use regex::{Captures, Regex};
+
+/// Compile a search pattern. With `smartcase`, a pattern containing
+/// uppercase letters is matched case-sensitively even with `ignorecase`.
+pub fn compile(pattern :&str, ignorecase :bool, smartcase :bool) -> Result<Regex, String> {
+    let ignore_case = ignorecase && !(smartcase && pattern.chars().any(char::is_uppercase));
+    Regex::new(pattern, ignore_case)
+}
+
+/// The matches of `regex` on `line`, with positions in the document.
+/// Matches do not span line breaks.
+pub fn line_matches(doc :&Document, regex :&Regex, line :usize) -> Vec<Captures> {
+    let end = doc.line_start(line) + doc.line_len(line);
+    let mut matches = Vec::new();
+    let mut pos = doc.line_start(line);
+    while pos <= end {
+        let found = match match_from(doc, regex, pos) {
+            Some(found) => found,
+            None => break,
+        };
+        // Continue after the match, or after the next character if it was
+        // empty.
+        pos = if found.end() > found.start() { found.end() } else { found.end() + 1 };
+        matches.push(found);
+    }
+    matches
+}
+
+/// The first match of `regex` starting at or after `pos` on the line of
+/// `pos`, with positions in the document.
+pub fn match_from(doc :&Document, regex :&Regex, pos :usize) -> Option<Captures> {
+    let line = doc.line_of(pos);
+    let start = doc.line_start(line);
+    let end = start + doc.line_len(line);
+    let prev = if pos == start { None } else { Some(doc.char_at(pos - 1)) };
+    let chars = doc.chunks(pos..end).flat_map(|chunk| chunk.chars());
+    regex.find(chars, prev).map(|found| found.offset(pos))
+}
+
+/// The first match starting after `pos`, or the last one starting before
+/// it if `backward` is set. The search wraps around the ends of the
+/// document; the flag returned is set if it did.
+pub fn find(doc :&Document, regex :&Regex, pos :usize, backward :bool) -> Option<(Captures, bool)> {
+    let line = doc.line_of(pos);
+    let count = doc.line_count();
+    // Every line is visited, and the line of `pos` once more after wrapping.
+    for i in 0..count + 1 {
+        let wrapped = if backward { i > line } else { line + i >= count };
+        let current = if backward { (line + count - i % count) % count } else { (line + i) % count };
+        let matches = line_matches(doc, regex, current);
+        let found = if backward {
+            matches.into_iter().rev().find(|m| i > 0 || m.start() < pos)
+        } else {
+            matches.into_iter().find(|m| i > 0 || m.start() > pos)
+        };
+        if let Some(found) = found {
+            return Some((found, wrapped));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compile, find, line_matches};
+    use document::Document;
+
+    #[test]
+    fn search() {
+        let mut doc = Document::new("foo bar\nbaz foo\n\nfoo".to_string());
+        // Split the pieces so that matches span them.
+        doc.insert_str(1, "o");
+        doc.remove(2);
+        let regex = compile("fo+", true, true).unwrap();
+        let starts = |line| line_matches(&doc, &regex, line).iter().map(|m| (m.start(), m.end())).collect::<Vec<_>>();
+        assert_eq!(starts(0), vec![(0, 3)]);
+        assert_eq!(starts(1), vec![(12, 15)]);
+        assert_eq!(starts(2), vec![]);
+
+        let at = |pos, backward| find(&doc, &regex, pos, backward).map(|(m, wrapped)| (m.start(), wrapped));
+        assert_eq!(at(0, false), Some((12, false)));
+        assert_eq!(at(12, false), Some((17, false)));
+        assert_eq!(at(17, false), Some((0, true)));
+        assert_eq!(at(12, true), Some((0, false)));
+        assert_eq!(at(0, true), Some((17, true)));
+        assert_eq!(at(13, true), Some((12, false)));
+
+        let empty = compile("x*", true, true).unwrap();
+        assert_eq!(line_matches(&doc, &empty, 0).len(), 8);
+        assert_eq!(line_matches(&doc, &empty, 2).len(), 1);
+
+        assert!(compile("Foo", true, true).unwrap().find("foo".chars(), None).is_none());
+        assert!(compile("foo", true, true).unwrap().find("FOO".chars(), None).is_some());
+        assert!(compile("foo", false, true).unwrap().find("FOO".chars(), None).is_none());
+    }
+}
@@ -0,0 +1,189 @@
+use document::Document;
+use regex::{Captures, Regex};
+use search;
+
+/// The text replacing `found`: `&` and `\0` stand for the whole match,
+/// `\1` to `\9` for groups, `\n` and `\r` for a line break and `\t` for a
+/// tab. Other escaped characters stand for themselves.
+pub fn expand(replacement :&str, doc :&Document, found :&Captures) -> String {
+    let mut text = String::new();
+    let group = |text :&mut String, i| if let Some(range) = found.get(i) {
+        text.push_str(&doc.slice(range));
+    };
+    let mut chars = replacement.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '&' => group(&mut text, 0),
+            '\\' => match chars.next() {
+                Some(d) if d.is_ascii_digit() => group(&mut text, d.to_digit(10).unwrap() as usize),
+                Some('n') | Some('r') => text.push('\n'),
+                Some('t') => text.push('\t'),
+                Some(other) => text.push(other),
+                None => text.push('\\'),
+            },
+            c => text.push(c),
+        }
+    }
+    text
+}
+
+/// A `:s` command in progress. Matches are visited one at a time so that
+/// each can be confirmed; the document may only change through `replace`
+/// while it is running.
+pub struct Substitution {
+    regex :Regex,
+    replacement :String,
+    global :bool,
+    /// Where to look for the next match.
+    pos :usize,
+    /// The end of the last line in the range.
+    end :usize,
+    /// The end of the last match visited. An empty match there is skipped.
+    last_end :Option<usize>,
+    current :Option<Captures>,
+    /// Number of replacements made.
+    pub count :usize,
+    /// Number of lines with replacements.
+    pub lines :usize,
+    /// The line of the last replacement.
+    pub last_line :Option<usize>,
+}
+
+impl Substitution {
+    /// Start substituting in the lines `first..=last`.
+    pub fn new(doc :&Document, regex :Regex, replacement :String, global :bool, first :usize, last :usize) -> Self {
+        let mut substitution = Substitution {
+            regex, replacement, global,
+            pos: doc.line_start(first),
+            end: doc.line_start(last) + doc.line_len(last),
+            last_end: None,
+            current: None,
+            count: 0,
+            lines: 0,
+            last_line: None,
+        };
+        substitution.find_next(doc);
+        substitution
+    }
+
+    /// The match to replace or skip next, or `None` when done.
+    pub fn current(&self) -> Option<&Captures> {
+        self.current.as_ref()
+    }
+
+    pub fn replacement(&self) -> &str {
+        &self.replacement
+    }
+
+    fn find_next(&mut self, doc :&Document) {
+        self.current = None;
+        while self.pos <= self.end {
+            let line = doc.line_of(self.pos);
+            // The search goes on from `pos`, so earlier parts of the line are
+            // not searched again after each replacement.
+            match search::match_from(doc, &self.regex, self.pos) {
+                Some(ref found) if found.start() == found.end() && Some(found.start()) == self.last_end => {
+                    self.pos = found.end() + 1;
+                },
+                Some(found) => {
+                    self.current = Some(found);
+                    return;
+                },
+                None if line + 1 < doc.line_count() => self.pos = doc.line_start(line + 1),
+                None => return,
+            }
+        }
+    }
+
+    /// Continue after a match or replacement ending at `end`.
+    fn advance(&mut self, doc :&Document, end :usize) {
+        self.last_end = Some(end);
+        self.pos = if self.global {
+            end
+        } else {
+            let line = doc.line_of(end);
+            if line + 1 < doc.line_count() { doc.line_start(line + 1) } else { self.end + 1 }
+        };
+        self.find_next(doc);
+    }
+
+    /// Replace the current match and find the next.
+    pub fn replace(&mut self, doc :&mut Document) {
+        let found = match self.current.take() {
+            Some(found) => found,
+            None => return,
+        };
+        let text = expand(&self.replacement, doc, &found);
+        let line = doc.line_of(found.start());
+        if found.end() > found.start() {
+            doc.remove_range(found.start()..found.end());
+        }
+        if !text.is_empty() {
+            doc.insert_str(found.start(), &text);
+        }
+        let len = text.chars().count();
+        self.end = self.end + len - (found.end() - found.start());
+        self.count += 1;
+        if self.last_line != Some(line) {
+            self.lines += 1;
+            self.last_line = Some(line);
+        }
+        self.advance(doc, found.start() + len);
+    }
+
+    /// Leave the current match and find the next.
+    pub fn skip(&mut self, doc :&Document) {
+        if let Some(found) = self.current.take() {
+            self.advance(doc, found.end());
+        }
+    }
+
+    pub fn replace_all(&mut self, doc :&mut Document) {
+        while self.current.is_some() {
+            self.replace(doc);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Substitution;
+    use document::Document;
+    use regex::Regex;
+
+    fn substitute(text :&str, pattern :&str, replacement :&str, global :bool, lines :(usize, usize)) -> (String, usize, usize) {
+        let mut doc = Document::new(text.to_string());
+        let regex = Regex::new(pattern, false).unwrap();
+        let mut substitution = Substitution::new(&doc, regex, replacement.to_string(), global, lines.0, lines.1);
+        substitution.replace_all(&mut doc);
+        (doc.to_string(), substitution.count, substitution.lines)
+    }
+
+    #[test]
+    fn substitution() {
+        assert_eq!(substitute("a a\na", "a", "b", false, (0, 0)), ("b a\na".to_string(), 1, 1));
+        assert_eq!(substitute("a a\na", "a", "b", false, (0, 1)), ("b a\nb".to_string(), 2, 2));
+        assert_eq!(substitute("a a\na\na", "a", "bb", true, (0, 1)), ("bb bb\nbb\na".to_string(), 3, 2));
+        assert_eq!(substitute("ab cd", "(\\w)(\\w)", "\\2\\1&", true, (0, 0)), ("baab dccd".to_string(), 2, 1));
+        assert_eq!(substitute("abc", "x*", "-", true, (0, 0)), ("-a-b-c-".to_string(), 4, 1));
+        assert_eq!(substitute("aab", "a*", "-", true, (0, 0)), ("-b-".to_string(), 2, 1));
+        assert_eq!(substitute("a,b\nc,d", ",", "\\n", false, (0, 1)), ("a\nb\nc\nd".to_string(), 2, 2));
+        assert_eq!(substitute("aa\naa", "a", "", true, (0, 1)), ("\n".to_string(), 4, 2));
+        assert_eq!(substitute("x\n\ny", "^$", "e", false, (0, 2)), ("x\ne\ny".to_string(), 1, 1));
+        let long = "ab ".repeat(2000);
+        assert_eq!(substitute(&long, "b", "", true, (0, 0)), ("a ".repeat(2000), 2000, 1));
+    }
+
+    #[test]
+    fn skipping() {
+        let mut doc = Document::new("a a a".to_string());
+        let regex = Regex::new("a", false).unwrap();
+        let mut substitution = Substitution::new(&doc, regex, "b".to_string(), true, 0, 0);
+        substitution.skip(&doc);
+        substitution.replace(&mut doc);
+        assert_eq!(substitution.current().map(|m| m.start()), Some(4));
+        substitution.skip(&doc);
+        assert!(substitution.current().is_none());
+        assert_eq!((doc.to_string(), substitution.count), ("a b a".to_string(), 1));
+    }
+}
@@ -0,0 +1,344 @@
+use document::Ref;
+use std::ops::{Add, Sub};
+use std::sync::Arc;
+
+/// Aggregated size of one or more pieces.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Weight {
+    pub bytes :usize,
+    pub chars :usize,
+    pub lines :usize,
+}
+
+impl Add for Weight {
+    type Output = Weight;
+    fn add(self, other :Weight) -> Weight {
+        Weight { bytes: self.bytes + other.bytes, chars: self.chars + other.chars, lines: self.lines + other.lines }
+    }
+}
+
+impl Sub for Weight {
+    type Output = Weight;
+    fn sub(self, other :Weight) -> Weight {
+        Weight { bytes: self.bytes - other.bytes, chars: self.chars - other.chars, lines: self.lines - other.lines }
+    }
+}
+
+/// Nodes are shared between clones of a treap, and copied on the way to
+/// a change when they are shared.
+type Link = Option<Arc<Node>>;
+
+#[derive(Clone)]
+struct Node {
+    priority :u64,
+    value :Ref,
+    weight :Weight,
+    sum :Weight,
+    count :usize,
+    left :Link,
+    right :Link,
+}
+
+fn count(link :&Link) -> usize {
+    link.as_ref().map(|n| n.count).unwrap_or(0)
+}
+
+fn sum(link :&Link) -> Weight {
+    link.as_ref().map(|n| n.sum).unwrap_or_default()
+}
+
+impl Node {
+    fn update(&mut self) {
+        self.count = count(&self.left) + 1 + count(&self.right);
+        self.sum = sum(&self.left) + self.weight + sum(&self.right);
+    }
+}
+
+fn merge(a :Link, b :Link) -> Link {
+    match (a, b) {
+        (None, b) => b,
+        (a, None) => a,
+        (Some(mut a), Some(mut b)) => {
+            if a.priority > b.priority {
+                let node = Arc::make_mut(&mut a);
+                node.right = merge(node.right.take(), Some(b));
+                node.update();
+                Some(a)
+            } else {
+                let node = Arc::make_mut(&mut b);
+                node.left = merge(Some(a), node.left.take());
+                node.update();
+                Some(b)
+            }
+        }
+    }
+}
+
+/// Split into the first `k` nodes and the rest.
+fn split(link :Link, k :usize) -> (Link, Link) {
+    match link {
+        None => (None, None),
+        Some(mut link) => {
+            let n = Arc::make_mut(&mut link);
+            let left_count = count(&n.left);
+            if k <= left_count {
+                let (l, r) = split(n.left.take(), k);
+                n.left = r;
+                n.update();
+                (l, Some(link))
+            } else {
+                let (l, r) = split(n.right.take(), k - left_count - 1);
+                n.right = l;
+                n.update();
+                (Some(link), r)
+            }
+        }
+    }
+}
+
+fn set(link :&mut Link, idx :usize, value :Ref, weight :Weight) {
+    let node = Arc::make_mut(link.as_mut().expect("Piece index out of bounds"));
+    let left_count = count(&node.left);
+    if idx < left_count {
+        set(&mut node.left, idx, value, weight);
+    } else if idx == left_count {
+        node.value = value;
+        node.weight = weight;
+    } else {
+        set(&mut node.right, idx - left_count - 1, value, weight);
+    }
+    node.update();
+}
+
+/// Implicit treap of pieces, ordered by position in the document. Each
+/// node aggregates the byte, character and line counts of its subtree, so
+/// that positional lookups and edits are O(log n). Cloning is O(1), and the
+/// clones share the nodes that neither has changed since.
+#[derive(Clone)]
+pub struct Treap {
+    root :Link,
+    seed :u64,
+}
+
+impl Treap {
+    pub fn new() -> Self {
+        Treap { root: None, seed: 0x2545_f491_4f6c_dd1d }
+    }
+
+    fn next_priority(&mut self) -> u64 {
+        // xorshift64
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        self.seed
+    }
+
+    /// Number of pieces.
+    pub fn len(&self) -> usize {
+        count(&self.root)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.root.is_none()
+    }
+
+    /// Total weight of all pieces.
+    pub fn sum(&self) -> Weight {
+        sum(&self.root)
+    }
+
+    pub fn get(&self, mut idx :usize) -> Ref {
+        let mut node = self.root.as_ref().expect("Piece index out of bounds");
+        loop {
+            let left_count = count(&node.left);
+            if idx < left_count {
+                node = node.left.as_ref().unwrap();
+            } else if idx == left_count {
+                return node.value;
+            } else {
+                idx -= left_count + 1;
+                node = node.right.as_ref().expect("Piece index out of bounds");
+            }
+        }
+    }
+
+    pub fn insert(&mut self, idx :usize, value :Ref, weight :Weight) {
+        let node = Arc::new(Node {
+            priority: self.next_priority(),
+            value,
+            weight,
+            sum: weight,
+            count: 1,
+            left: None,
+            right: None,
+        });
+        let (first, last) = split(self.root.take(), idx);
+        self.root = merge(merge(first, Some(node)), last);
+    }
+
+    pub fn remove(&mut self, idx :usize) -> Ref {
+        let (first, rest) = split(self.root.take(), idx);
+        let (node, last) = split(rest, 1);
+        self.root = merge(first, last);
+        node.expect("Piece index out of bounds").value
+    }
+
+    pub fn set(&mut self, idx :usize, value :Ref, weight :Weight) {
+        set(&mut self.root, idx, value, weight);
+    }
+
+    /// Total weight of the pieces before `idx`.
+    pub fn prefix(&self, mut idx :usize) -> Weight {
+        let mut acc = Weight::default();
+        let mut link = &self.root;
+        while let Some(ref node) = *link {
+            let left_count = count(&node.left);
+            if idx <= left_count {
+                link = &node.left;
+            } else {
+                acc = acc + sum(&node.left) + node.weight;
+                idx -= left_count + 1;
+                link = &node.right;
+            }
+        }
+        acc
+    }
+
+    /// Find the first piece for which `key` of the accumulated weight up to
+    /// and including the piece exceeds `target`. Returns the piece index and
+    /// the weight of the pieces before it, or `(len, sum)` if there is none.
+    fn find<F: Fn(&Weight) -> usize>(&self, target :usize, key :F) -> (usize, Weight) {
+        let mut idx = 0;
+        let mut acc = Weight::default();
+        let mut link = &self.root;
+        while let Some(ref node) = *link {
+            let left = acc + sum(&node.left);
+            if key(&left) > target {
+                link = &node.left;
+            } else if key(&(left + node.weight)) > target {
+                return (idx + count(&node.left), left);
+            } else {
+                idx += count(&node.left) + 1;
+                acc = left + node.weight;
+                link = &node.right;
+            }
+        }
+        (idx, acc)
+    }
+
+    /// The piece containing the character index `idx`.
+    pub fn find_char(&self, idx :usize) -> (usize, Weight) {
+        self.find(idx, |w| w.chars)
+    }
+
+    /// The piece containing the byte offset `idx`.
+    pub fn find_byte(&self, idx :usize) -> (usize, Weight) {
+        self.find(idx, |w| w.bytes)
+    }
+
+    /// The piece containing the line break which ends line `line`.
+    pub fn find_line(&self, line :usize) -> (usize, Weight) {
+        self.find(line, |w| w.lines)
+    }
+
+    /// In-order iterator starting at the piece `idx`.
+    pub fn iter_from<'a>(&'a self, mut idx :usize) -> Iter<'a> {
+        let mut iter = Iter { stack: Vec::new() };
+        let mut link = &self.root;
+        while let Some(ref node) = *link {
+            let left_count = count(&node.left);
+            if idx <= left_count {
+                iter.stack.push(node);
+                link = &node.left;
+            } else {
+                idx -= left_count + 1;
+                link = &node.right;
+            }
+        }
+        iter
+    }
+}
+
+/// In-order iterator over the pieces.
+pub struct Iter<'a> {
+    stack :Vec<&'a Node>,
+}
+
+impl<'a> Iter<'a> {
+    fn push_left(&mut self, mut link :&'a Link) {
+        while let Some(ref node) = *link {
+            self.stack.push(node);
+            link = &node.left;
+        }
+    }
+}
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Ref;
+    fn next(&mut self) -> Option<&'a Ref> {
+        let node = self.stack.pop()?;
+        self.push_left(&node.right);
+        Some(&node.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Treap, Weight};
+    use document::Ref;
+    use rand::{thread_rng, Rng};
+
+    fn weight(r :&Ref) -> Weight {
+        Weight { bytes: 2 * r.len(), chars: r.len(), lines: r.len() % 3 }
+    }
+
+    #[test]
+    fn randoms() {
+        let mut rng = thread_rng();
+        let mut treap = Treap::new();
+        let mut model :Vec<Ref> = Vec::new();
+        let mut saved = (Treap::new(), Vec::new());
+        for i in 0..2000 {
+            if i == 1000 {
+                saved = (treap.clone(), model.clone());
+            }
+            let r = Ref::Append(i, rng.gen_range(1, 10));
+            match rng.gen_range(0, 3) {
+                0 | 1 if model.len() < 300 => {
+                    let idx = rng.gen_range(0, model.len() + 1);
+                    treap.insert(idx, r, weight(&r));
+                    model.insert(idx, r);
+                },
+                _ if !model.is_empty() => {
+                    let idx = rng.gen_range(0, model.len());
+                    if rng.gen() {
+                        assert_eq!(treap.remove(idx), model.remove(idx));
+                    } else {
+                        treap.set(idx, r, weight(&r));
+                        model[idx] = r;
+                    }
+                },
+                _ => {},
+            }
+            assert_eq!(treap.len(), model.len());
+        }
+
+        assert_eq!(treap.iter_from(0).cloned().collect::<Vec<_>>(), model);
+        for i in 0..model.len() + 1 {
+            assert_eq!(treap.iter_from(i).cloned().collect::<Vec<_>>(), &model[i..]);
+        }
+        let mut acc = Weight::default();
+        for (i,r) in model.iter().enumerate() {
+            assert_eq!(treap.get(i), *r);
+            assert_eq!(treap.prefix(i), acc);
+            assert_eq!(treap.find_char(acc.chars), (i, acc));
+            assert_eq!(treap.find_char(acc.chars + r.len() - 1), (i, acc));
+            acc = acc + weight(r);
+        }
+        assert_eq!(treap.sum(), acc);
+        assert_eq!(treap.find_char(acc.chars), (model.len(), acc));
+        // A clone is not changed by edits made after it.
+        assert_eq!(saved.0.iter_from(0).cloned().collect::<Vec<_>>(), saved.1);
+        assert_eq!(saved.0.sum(), saved.1.iter().fold(Weight::default(), |acc, r| acc + weight(r)));
+    }
+}
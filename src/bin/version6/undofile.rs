@@ -0,0 +1,252 @@
+use document::{Document, Snapshot};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// The start of every undo file.
+const MAGIC :&[u8] = b"version6 undo\n";
+/// Undo files written in another version of the format are ignored.
+pub const VERSION :u64 = 2;
+/// Files larger than this get no undo file, since a restored document
+/// holds all of its text in memory instead of mapping the file.
+pub const MAX_SIZE :u64 = 1 << 26;
+
+const FNV_OFFSET :u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME :u64 = 0x0100_0000_01b3;
+
+/// 64-bit FNV-1a hash of `bytes`, continuing from `hash`.
+fn fnv(hash :u64, bytes :&[u8]) -> u64 {
+    bytes.iter().fold(hash, |hash, b| (hash ^ u64::from(*b)).wrapping_mul(FNV_PRIME))
+}
+
+pub fn checksum(bytes :&[u8]) -> u64 {
+    fnv(FNV_OFFSET, bytes)
+}
+
+/// Checksum of the contents of the file at `path`, read a part at a time.
+fn file_checksum(path :&Path) -> io::Result<u64> {
+    let mut file = fs::File::open(path)?;
+    let mut buf = vec![0; 1 << 16];
+    let mut hash = FNV_OFFSET;
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => return Ok(hash),
+            Ok(len) => hash = fnv(hash, &buf[..len]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Checksum of the text of `doc`, the same as that of the file it was
+/// written to.
+pub fn text_checksum(doc :&Snapshot) -> u64 {
+    doc.chunks(0..doc.len()).fold(FNV_OFFSET, |hash, chunk| fnv(hash, chunk.as_bytes()))
+}
+
+/// Values written to an undo file, in little-endian order.
+#[derive(Default)]
+pub struct Encoder {
+    pub bytes :Vec<u8>,
+}
+
+impl Encoder {
+    pub fn u8(&mut self, value :u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u64(&mut self, value :u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, value :usize) {
+        self.u64(value as u64);
+    }
+}
+
+/// Reads back what an `Encoder` wrote.
+pub struct Decoder<'a> {
+    bytes :&'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(bytes :&'a [u8]) -> Self {
+        Decoder { bytes }
+    }
+
+    fn take(&mut self, len :usize) -> Result<&'a [u8], String> {
+        if len > self.bytes.len() {
+            return Err("Unexpected end of undo file".to_string());
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    pub fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn usize(&mut self) -> Result<usize, String> {
+        Ok(self.u64()? as usize)
+    }
+
+    /// Bytes preceded by their length.
+    pub fn bytes(&mut self) -> Result<&'a [u8], String> {
+        let len = self.usize()?;
+        self.take(len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+}
+
+/// The undo file kept for `path`: `.name.un~` in the same directory.
+pub fn path_for(path :&Path) -> Option<PathBuf> {
+    let name = path.file_name()?;
+    Some(path.with_file_name(format!(".{}.un~", name.to_string_lossy())))
+}
+
+/// Save the undo history of `doc`, which has just been written to `path`.
+/// The file holds the magic string, the format version, the checksum of
+/// the text, the document's buffers and history, and lastly the checksum
+/// of everything before it. Nothing is saved for files over `MAX_SIZE`.
+/// The history goes to a temporary file that is then renamed over the undo
+/// file, so a failed save leaves the old one whole.
+pub fn save(path :&Path, doc :&Document) -> io::Result<()> {
+    let undo_path = path_for(path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file name"))?;
+    if fs::metadata(path)?.len() > MAX_SIZE {
+        return remove(path);
+    }
+    let mut out = Encoder::default();
+    out.bytes.extend_from_slice(MAGIC);
+    out.u64(VERSION);
+    out.u64(text_checksum(doc));
+    doc.encode_history(&mut out);
+    let sum = checksum(&out.bytes);
+    out.u64(sum);
+    let tmp_path = undo_path.with_extension("un~.tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    let result = file.write_all(&out.bytes)
+        .and_then(|()| file.sync_all())
+        .and_then(|()| fs::rename(&tmp_path, &undo_path));
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Remove the undo file of `path`, if there is one.
+pub fn remove(path :&Path) -> io::Result<()> {
+    match path_for(path).map(fs::remove_file) {
+        Some(Err(ref e)) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Some(result) => result,
+        None => Ok(()),
+    }
+}
+
+/// The document saved in the undo file of `path`, with its history, or
+/// `None` if there is no undo file. The history is refused if the file
+/// no longer has the contents it was saved with, or is over `MAX_SIZE`.
+pub fn load(path :&Path) -> Result<Option<Document>, String> {
+    let undo_path = match path_for(path) {
+        Some(undo_path) => undo_path,
+        None => return Ok(None),
+    };
+    if !undo_path.exists() {
+        return Ok(None);
+    }
+    if fs::metadata(path).map_err(|e| e.to_string())?.len() > MAX_SIZE {
+        return Err("File is too large to restore its undo history".to_string());
+    }
+    let data = match fs::read(&undo_path) {
+        Ok(data) => data,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.to_string()),
+    };
+    if data.len() < MAGIC.len() + 8 || !data.starts_with(MAGIC) {
+        return Err("Not an undo file".to_string());
+    }
+    let (body, sum) = data.split_at(data.len() - 8);
+    if Decoder::new(sum).u64()? != checksum(body) {
+        return Err("Undo file is corrupt".to_string());
+    }
+    let mut input = Decoder::new(&body[MAGIC.len()..]);
+    let version = input.u64()?;
+    if version != VERSION {
+        return Err(format!("Unsupported undo file version {}", version));
+    }
+    let text_sum = input.u64()?;
+    if file_checksum(path).map_err(|e| e.to_string())? != text_sum {
+        return Err("File changed since its undo history was saved".to_string());
+    }
+    let doc = Document::decode_history(&mut input)?;
+    if !input.is_empty() || text_checksum(&doc) != text_sum {
+        return Err("Undo file is corrupt".to_string());
+    }
+    Ok(Some(doc))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::process;
+
+    #[test]
+    fn test_undofile() {
+        let dir = env::temp_dir().join(format!("version6-undofile-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("text.txt");
+        assert_eq!(path_for(&path), Some(dir.join(".text.txt.un~")));
+        fs::write(&path, "hello world\n").unwrap();
+        assert!(load(&path).unwrap().is_none());
+
+        let mut doc = Document::new(fs::read_to_string(&path).unwrap());
+        doc.insert_str(5, ", dear");
+        doc.remove_range(0..1);
+        doc.insert_str(0, "Hé");
+        doc.undo();
+        fs::write(&path, doc.to_string()).unwrap();
+        save(&path, &doc).unwrap();
+        assert!(!dir.join(".text.txt.un~.tmp").exists());
+
+        let mut loaded = load(&path).unwrap().unwrap();
+        assert_eq!(loaded.to_string(), "ello, dear world\n");
+        assert_eq!(loaded.redo(), Some(0));
+        assert_eq!(loaded.to_string(), "Héello, dear world\n");
+        assert_eq!(loaded.undo(), Some(0));
+        assert_eq!(loaded.undo(), Some(0));
+        assert_eq!(loaded.undo(), Some(5));
+        assert_eq!(loaded.to_string(), "hello world\n");
+        assert_eq!(loaded.undo(), None);
+        loaded.insert_str(0, "x");
+        assert_eq!(loaded.to_string(), "xhello world\n");
+        assert_eq!(loaded.earlier(1), Some(0));
+        assert_eq!(loaded.to_string(), "Héello, dear world\n");
+
+        let undo_path = path_for(&path).unwrap();
+        let mut data = fs::read(&undo_path).unwrap();
+        let middle = data.len() / 2;
+        data[middle] ^= 1;
+        fs::write(&undo_path, &data).unwrap();
+        assert_eq!(load(&path).err(), Some("Undo file is corrupt".to_string()));
+
+        save(&path, &doc).unwrap();
+        fs::write(&path, "hello world\n").unwrap();
+        assert_eq!(load(&path).err(), Some("File changed since its undo history was saved".to_string()));
+
+        remove(&path).unwrap();
+        remove(&path).unwrap();
+        assert!(load(&path).unwrap().is_none());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
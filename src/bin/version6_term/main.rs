@@ -0,0 +1,482 @@
+// Terminal frontend for the version6 editor: the same `Editor`, drawn with
+// ANSI escapes in a raw-mode terminal instead of an OpenGL window.
+extern crate rand;
+extern crate unicode_segmentation;
+extern crate unicode_width;
+
+#[path="../version6/document.rs"] mod document;
+#[path="../version6/treap.rs"] mod treap;
+
+#[path="../version6/command.rs"] mod command;
+#[path="../version6/editor.rs"] mod editor;
+#[path="../version6/getline.rs"] mod getline;
+#[path="../version6/input.rs"] mod input;
+#[path="../version6/motion.rs"] mod motion;
+#[path="../version6/normal.rs"] mod normal;
+#[path="../version6/options.rs"] mod options;
+#[path="../version6/regex.rs"] mod regex;
+#[path="../version6/register.rs"] mod register;
+#[path="../version6/renderer.rs"] mod renderer;
+#[path="../version6/search.rs"] mod search;
+#[path="../version6/substitute.rs"] mod substitute;
+#[path="../version6/undofile.rs"] mod undofile;
+
+use input::{InputEvent, Key};
+use renderer::{Color, Metrics, Rect, TextCommand};
+use std::io::{self, Read, Write};
+use std::os::raw::{c_int, c_short, c_ulong, c_ushort};
+use std::process::{Command, Stdio};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Size of a character cell in the coordinates given to `Editor::render`.
+const CELL :Metrics = Metrics { line_height: 20.0, glyph_width: 10.0 };
+
+/// Puts the terminal in raw mode, and restores the previous mode when
+/// dropped.
+struct RawMode {
+    saved :String,
+}
+
+fn stty(args :&[&str]) -> io::Result<String> {
+    let output = Command::new("stty").args(args).stdin(Stdio::inherit()).stderr(Stdio::inherit()).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other("stty failed"));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        let saved = stty(&["-g"])?;
+        stty(&["raw", "-echo"])?;
+        Ok(RawMode { saved })
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = stty(&[&self.saved]);
+    }
+}
+
+/// The window size of a terminal, as given by the TIOCGWINSZ ioctl.
+#[repr(C)]
+struct WinSize {
+    rows :c_ushort,
+    cols :c_ushort,
+    width :c_ushort,
+    height :c_ushort,
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const TIOCGWINSZ :c_ulong = 0x5413;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const TIOCGWINSZ :c_ulong = 0x4008_7468;
+
+extern "C" {
+    fn ioctl(fd :c_int, request :c_ulong, ...) -> c_int;
+}
+
+/// The terminal size in rows and columns. This is asked for on every
+/// redraw, so it comes from an ioctl on the output rather than from stty.
+fn terminal_size() -> (usize, usize) {
+    let mut size = WinSize { rows: 0, cols: 0, width: 0, height: 0 };
+    if unsafe { ioctl(1, TIOCGWINSZ, &mut size as *mut WinSize) } == 0 && size.rows > 0 && size.cols > 0 {
+        return (size.rows as usize, size.cols as usize);
+    }
+    stty(&["size"]).ok()
+        .and_then(|size| {
+            let mut parts = size.split_whitespace().map(|n| n.parse::<usize>());
+            match (parts.next(), parts.next()) {
+                (Some(Ok(rows)), Some(Ok(cols))) if rows > 0 && cols > 0 => Some((rows, cols)),
+                _ => None,
+            }
+        })
+        .unwrap_or((24, 80))
+}
+
+#[derive(Clone, PartialEq)]
+struct Cell {
+    /// A grapheme cluster, or nothing if the cell is covered by a wide
+    /// cluster to its left.
+    text :String,
+    fg :Color,
+    bg :Color,
+}
+
+const BACKGROUND :Color = [0.08, 0.02, 0.02, 1.0];
+
+/// A grid of character cells which `TextCommand`s are drawn into.
+struct Screen {
+    rows :usize,
+    cols :usize,
+    cells :Vec<Cell>,
+}
+
+impl Screen {
+    fn new(rows :usize, cols :usize) -> Self {
+        Screen { rows, cols, cells: vec![Cell { text: " ".to_string(), fg: [1.0; 4], bg: BACKGROUND }; rows * cols] }
+    }
+
+    /// The cells covered by `rect`, at least one wide and high.
+    fn cells(&self, rect :Rect) -> (usize, usize, usize, usize) {
+        let col = |x :f32| usize::min(self.cols, (x / CELL.glyph_width).round().max(0.0) as usize);
+        let row = |y :f32| usize::min(self.rows, (y / CELL.line_height).round().max(0.0) as usize);
+        let (c0, r0) = (col((rect.0).0), row((rect.0).1));
+        let (c1, r1) = (col((rect.1).0), row((rect.1).1));
+        (r0, c0, usize::max(r1, r0 + 1), usize::max(c1, c0 + 1))
+    }
+
+    /// Draw a command like the glyph renderer: fill the background, then
+    /// put the text on the first row of `rect`. Returns the text bounds.
+    fn draw(&mut self, cmd :&TextCommand) -> Option<Rect> {
+        let (r0, c0, r1, c1) = self.cells(cmd.rect);
+        if let Some(bg) = cmd.bg {
+            for row in r0..usize::min(r1, self.rows) {
+                for col in c0..usize::min(c1, self.cols) {
+                    self.cells[row * self.cols + col].bg = bg;
+                }
+            }
+        }
+        if r0 >= self.rows {
+            return None;
+        }
+        let end = usize::min(c1, self.cols);
+        let mut col = c0;
+        for grapheme in cmd.text.graphemes(true) {
+            let width = document::width(grapheme);
+            if col + width > end { break; }
+            let row = &mut self.cells[r0 * self.cols..(r0 + 1) * self.cols];
+            row[col].text = grapheme.to_string();
+            row[col].fg = cmd.fg;
+            for cell in &mut row[col + 1..col + width] {
+                cell.text = String::new();
+            }
+            // Don't leave half of a wide cluster that was drawn over.
+            if let Some(next) = row.get_mut(col + width) {
+                if next.text.is_empty() { next.text = " ".to_string(); }
+            }
+            col += width;
+        }
+        if cmd.text.trim().is_empty() {
+            return None;
+        }
+        let (x, y) = cmd.rect.0;
+        let width = CELL.glyph_width * (col - c0) as f32;
+        Some(((x, y), (x + width, y + CELL.line_height)))
+    }
+
+    fn write<W: Write>(&self, out :&mut W) -> io::Result<()> {
+        let mut last :Option<(Color, Color)> = None;
+        write!(out, "\x1b[?25l\x1b[H")?;
+        for row in 0..self.rows {
+            write!(out, "\x1b[{};1H", row + 1)?;
+            for cell in &self.cells[row * self.cols..(row + 1) * self.cols] {
+                if last != Some((cell.fg, cell.bg)) {
+                    let (fg, bg) = (rgb(cell.fg), rgb(cell.bg));
+                    write!(out, "\x1b[38;2;{};{};{}m\x1b[48;2;{};{};{}m", fg.0, fg.1, fg.2, bg.0, bg.1, bg.2)?;
+                    last = Some((cell.fg, cell.bg));
+                }
+                if cell.text.chars().any(char::is_control) {
+                    write!(out, " ")?;
+                } else {
+                    write!(out, "{}", cell.text)?;
+                }
+            }
+        }
+        write!(out, "\x1b[0m")?;
+        out.flush()
+    }
+}
+
+fn rgb(color :Color) -> (u8, u8, u8) {
+    let c = |v :f32| (v.clamp(0.0, 1.0) * 255.0).round() as u8;
+    (c(color[0]), c(color[1]), c(color[2]))
+}
+
+/// Translate bytes read from the terminal to editor input. A lone escape
+/// byte is the Escape key; known escape sequences are cursor keys and
+/// mouse reports. Also returns the number of bytes used: unless `complete`
+/// is set, a character or escape sequence cut off at the end is left for
+/// the next read to finish.
+fn parse_input(bytes :&[u8], complete :bool) -> (Vec<InputEvent>, usize) {
+    let mut events = Vec::new();
+    let end = if complete { bytes.len() } else { bytes.len() - unfinished_char(bytes) };
+    let text = String::from_utf8_lossy(&bytes[..end]);
+    let chars = text.chars().collect::<Vec<_>>();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        if c == '\x1b' {
+            match escape_sequence(&chars[i..], complete) {
+                Sequence::Known(event, len) => {
+                    events.extend(event);
+                    i += len;
+                    continue;
+                },
+                // Sequences are ASCII, so the characters left are bytes.
+                Sequence::Unfinished => return (events, end - (chars.len() - i + 1)),
+                Sequence::Not => {},
+            }
+        }
+        let key = match c {
+            '\x1b' => Key::Escape,
+            '\r' | '\n' => Key::Enter,
+            '\x7f' | '\x08' => Key::Backspace,
+            '\x01'..='\x1a' => {
+                events.push(InputEvent::ctrl((b'a' + c as u8 - 1) as char));
+                continue;
+            },
+            c if c.is_control() => continue,
+            c => Key::Char(c),
+        };
+        events.push(InputEvent::key(key));
+    }
+    (events, end)
+}
+
+/// Length of a UTF-8 character cut off at the end of `bytes`.
+fn unfinished_char(bytes :&[u8]) -> usize {
+    for len in 1..=usize::min(3, bytes.len()) {
+        let b = bytes[bytes.len() - len];
+        if b & 0xc0 == 0x80 { continue; }
+        let needed = if b >= 0xf0 { 4 } else if b >= 0xe0 { 3 } else if b >= 0xc0 { 2 } else { 1 };
+        return if needed > len { len } else { 0 };
+    }
+    0
+}
+
+/// What follows an ESC.
+enum Sequence {
+    /// A known CSI or SS3 sequence, its event if it has one, and the number
+    /// of characters it takes up. Mouse reports other than the wheel have
+    /// no event.
+    Known(Option<InputEvent>, usize),
+    /// The start of a sequence, which goes on in the next read.
+    Unfinished,
+    /// Not a sequence, but other keys.
+    Not,
+}
+
+/// The sequence at the start of `chars`, which follow an ESC. When
+/// `complete` is set, nothing more will follow them.
+fn escape_sequence(chars :&[char], complete :bool) -> Sequence {
+    let (&intro, rest) = match chars.split_first() {
+        Some(split) => split,
+        None if complete => return Sequence::Not,
+        None => return Sequence::Unfinished,
+    };
+    let params = match intro {
+        '[' => rest.iter().take_while(|c| ('0'..='?').contains(*c)).count(),
+        'O' => 0,
+        _ => return Sequence::Not,
+    };
+    match rest.get(params) {
+        Some(c) if ('@'..='~').contains(c) => {},
+        None if !complete => return Sequence::Unfinished,
+        _ => return Sequence::Not,
+    }
+    let sequence = rest[..=params].iter().collect::<String>();
+    let key = |key| Some(InputEvent::key(key));
+    let event = match (intro, sequence.as_str()) {
+        (_, "A") => key(Key::Up),
+        (_, "B") => key(Key::Down),
+        (_, "C") => key(Key::Right),
+        (_, "D") => key(Key::Left),
+        (_, "H") | ('[', "1~") | ('[', "7~") => key(Key::Home),
+        (_, "F") | ('[', "4~") | ('[', "8~") => key(Key::End),
+        ('[', "3~") => key(Key::Delete),
+        // Mouse reports in SGR mouse mode.
+        ('[', s) if s.starts_with("<64;") && s.ends_with('M') => Some(InputEvent::Scroll(-3)),
+        ('[', s) if s.starts_with("<65;") && s.ends_with('M') => Some(InputEvent::Scroll(3)),
+        ('[', s) if s.starts_with('<') && (s.ends_with('M') || s.ends_with('m')) => None,
+        _ => return Sequence::Not,
+    };
+    Sequence::Known(event, 1 + params + 1)
+}
+
+#[repr(C)]
+struct PollFd {
+    fd :c_int,
+    events :c_short,
+    revents :c_short,
+}
+
+const POLLIN :c_short = 1;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+type Nfds = c_ulong;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+type Nfds = ::std::os::raw::c_uint;
+
+extern "C" {
+    fn poll(fds :*mut PollFd, nfds :Nfds, timeout :c_int) -> c_int;
+}
+
+/// How long the rest of an escape sequence may take to arrive, in
+/// milliseconds, before its start is taken as keys.
+const ESCAPE_TIMEOUT :c_int = 50;
+
+/// Whether there is input to read within `timeout` milliseconds.
+fn input_ready(timeout :c_int) -> bool {
+    let mut fd = PollFd { fd: 0, events: POLLIN, revents: 0 };
+    unsafe { poll(&mut fd, 1, timeout) > 0 }
+}
+
+fn run(editor :&mut editor::Editor) -> io::Result<()> {
+    let stdin = io::stdin();
+    let stdout = io::stdout();
+    let mut stdin = stdin.lock();
+    let mut stdout = stdout.lock();
+    let mut buf = [0u8; 64];
+    // Bytes read but not yet used, which start a character or sequence.
+    let mut pending = Vec::new();
+    let mut redraw = true;
+    // While a file loads, reads time out after a tenth of a second so that
+    // it can be polled.
+    let mut loading = editor.loading();
+    if loading {
+        stty(&["min", "0", "time", "1"])?;
+    }
+    loop {
+        redraw |= editor.poll();
+        if loading && !editor.loading() {
+            loading = false;
+            stty(&["min", "1", "time", "0"])?;
+        }
+        if redraw {
+            let (rows, cols) = terminal_size();
+            let mut screen = Screen::new(rows, cols);
+            let size = (cols as f32 * CELL.glyph_width, rows as f32 * CELL.line_height);
+            editor.resize(size, CELL);
+            editor.render(size, CELL, |cmd| screen.draw(cmd));
+            screen.write(&mut stdout)?;
+            redraw = false;
+        }
+
+        let events = if !pending.is_empty() && !input_ready(ESCAPE_TIMEOUT) {
+            // The rest of a sequence did not come, so take what there is.
+            let (events, _) = parse_input(&pending, true);
+            pending.clear();
+            events
+        } else {
+            let n = stdin.read(&mut buf)?;
+            if n == 0 {
+                if loading { continue; }
+                return Ok(());
+            }
+            pending.extend_from_slice(&buf[..n]);
+            let (events, used) = parse_input(&pending, false);
+            pending.drain(..used);
+            events
+        };
+        redraw = true;
+        for event in events {
+            editor.input(event);
+        }
+        if editor.quit_requested() {
+            return Ok(());
+        }
+    }
+}
+
+fn main() {
+    let mut editor = match std::env::args_os().nth(1) {
+        Some(path) => editor::Editor::open(path.into()),
+        None => editor::Editor::new(),
+    };
+    let result = {
+        let _raw = match RawMode::enable() {
+            Ok(raw) => raw,
+            Err(e) => {
+                eprintln!("Could not set up the terminal: {}", e);
+                std::process::exit(1);
+            },
+        };
+        // Alternate screen, and mouse reporting for the wheel.
+        print!("\x1b[?1049h\x1b[?1000h\x1b[?1006h");
+        let result = run(&mut editor);
+        print!("\x1b[?1006l\x1b[?1000l\x1b[0m\x1b[?25h\x1b[?1049l");
+        let _ = io::stdout().flush();
+        result
+    };
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_input, Screen, CELL};
+    use input::{InputEvent, Key};
+    use renderer::TextCommand;
+
+    #[test]
+    fn keys() {
+        assert_eq!(parse_input(b"a\x1b\x1b[A\x1b[3~\x12\x7f\r", true).0, vec![
+            InputEvent::key(Key::Char('a')),
+            InputEvent::key(Key::Escape),
+            InputEvent::key(Key::Up),
+            InputEvent::key(Key::Delete),
+            InputEvent::ctrl('r'),
+            InputEvent::key(Key::Backspace),
+            InputEvent::key(Key::Enter),
+        ]);
+        assert_eq!(parse_input(b"\x1b[<65;3;4M\x1b[<0;3;4m", true).0, vec![InputEvent::Scroll(3)]);
+        assert_eq!(parse_input("æ".as_bytes(), true).0, vec![InputEvent::key(Key::Char('æ'))]);
+        // An ESC not starting a known sequence is a key of its own.
+        assert_eq!(parse_input(b"\x1bOx\x1b[12zb\x1b[", true).0, vec![
+            InputEvent::key(Key::Escape),
+            InputEvent::key(Key::Char('O')),
+            InputEvent::key(Key::Char('x')),
+            InputEvent::key(Key::Escape),
+            InputEvent::key(Key::Char('[')),
+            InputEvent::key(Key::Char('1')),
+            InputEvent::key(Key::Char('2')),
+            InputEvent::key(Key::Char('z')),
+            InputEvent::key(Key::Char('b')),
+            InputEvent::key(Key::Escape),
+            InputEvent::key(Key::Char('[')),
+        ]);
+        assert_eq!(parse_input(b"\x1bOA\x1b[1~", true).0, vec![InputEvent::key(Key::Up), InputEvent::key(Key::Home)]);
+
+        // Characters and sequences cut off by the end of a read are left for
+        // the next one, and a lone ESC waits for the read to time out.
+        let mut pending = b"x\x1b[<65;12".to_vec();
+        assert_eq!(parse_input(&pending, false), (vec![InputEvent::key(Key::Char('x'))], 1));
+        pending.drain(..1);
+        pending.extend_from_slice(b";5M\xc3");
+        assert_eq!(parse_input(&pending, false), (vec![InputEvent::Scroll(3)], 11));
+        pending.drain(..11);
+        pending.extend_from_slice(b"\xa6\x1b");
+        assert_eq!(parse_input(&pending, false), (vec![InputEvent::key(Key::Char('æ'))], 2));
+        pending.drain(..2);
+        assert_eq!(parse_input(&pending, true), (vec![InputEvent::key(Key::Escape)], 1));
+    }
+
+    #[test]
+    fn cells() {
+        let mut screen = Screen::new(2, 4);
+        let line = |col :f32, row :f32| ((col * CELL.glyph_width, row * CELL.line_height),
+                                         (4.0 * CELL.glyph_width, 2.0 * CELL.line_height));
+        screen.draw(&TextCommand { text: "abcdef", rect: line(1.0, 1.0), fg: [1.0; 4], bg: None });
+        let cursor = ((2.0 * CELL.glyph_width, CELL.line_height), (2.0 * CELL.glyph_width + 2.0, 2.0 * CELL.line_height));
+        screen.draw(&TextCommand { text: "", rect: cursor, fg: [1.0; 4], bg: Some([0.0; 4]) });
+        let text = screen.cells.iter().map(|c| c.text.as_str()).collect::<String>();
+        assert_eq!(text, "     abc");
+        let dark = screen.cells.iter().map(|c| c.bg == [0.0; 4]).collect::<Vec<_>>();
+        assert_eq!(dark, vec![false, false, false, false, false, false, true, false]);
+
+        // Wide clusters take two cells, combining sequences one.
+        let mut screen = Screen::new(1, 5);
+        let row = ((0.0, 0.0), (5.0 * CELL.glyph_width, CELL.line_height));
+        let r = screen.draw(&TextCommand { text: "漢e\u{301}🇳🇴", rect: row, fg: [1.0; 4], bg: None });
+        let cells = screen.cells.iter().map(|c| c.text.as_str()).collect::<Vec<_>>();
+        assert_eq!(cells, vec!["漢", "", "e\u{301}", "🇳🇴", ""]);
+        assert_eq!(r.map(|r| (r.1).0), Some(5.0 * CELL.glyph_width));
+        screen.draw(&TextCommand { text: "x", rect: row, fg: [1.0; 4], bg: None });
+        assert_eq!(screen.cells[1].text, " ");
+    }
+}